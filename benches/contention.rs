@@ -0,0 +1,57 @@
+use std::hint::black_box;
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use phase2::atomic_token_bucket::AtomicTokenBucket;
+use phase2::token_bucket::TokenBucket;
+
+const CALLS_PER_THREAD: u64 = 200_000;
+
+fn run<F>(threads: usize, take: F) -> Duration
+where
+    F: Fn() -> bool + Send + Sync + 'static,
+{
+    let take = Arc::new(take);
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let take = Arc::clone(&take);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..CALLS_PER_THREAD {
+                    black_box(take());
+                }
+            })
+        })
+        .collect();
+    barrier.wait();
+    let start = Instant::now();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, threads: usize, elapsed: Duration) {
+    let calls = CALLS_PER_THREAD * threads as u64;
+    let per_sec = calls as f64 / elapsed.as_secs_f64();
+    println!("{name:>8} threads={threads:<3} {elapsed:>12.2?} {per_sec:>14.0} calls/s");
+}
+
+fn main() {
+    let max_threads = thread::available_parallelism().map_or(4, |n| n.get());
+    let mut threads = 1;
+    while threads <= max_threads {
+        let bucket = Mutex::new(TokenBucket::new(Duration::from_millis(1), 1_000, 1_000).unwrap());
+        let elapsed = run(threads, move || bucket.lock().unwrap().try_take().is_ok());
+        report("mutex", threads, elapsed);
+
+        let bucket = AtomicTokenBucket::new(Duration::from_millis(1), 1_000, 1_000).unwrap();
+        let elapsed = run(threads, move || bucket.try_take().is_ok());
+        report("atomic", threads, elapsed);
+
+        threads *= 2;
+    }
+}
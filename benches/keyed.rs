@@ -0,0 +1,62 @@
+use std::hint::black_box;
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use phase2::builder::TokenBucketBuilder;
+use phase2::keyed::KeyedTokenBuckets;
+use phase2::sharded::ShardedTokenBuckets;
+
+const CALLS_PER_THREAD: u64 = 200_000;
+const KEYS_PER_THREAD: u64 = 10_000;
+
+fn run<F>(threads: usize, take: F) -> Duration
+where
+    F: Fn(u64) -> bool + Send + Sync + 'static,
+{
+    let take = Arc::new(take);
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles: Vec<_> = (0..threads as u64)
+        .map(|thread| {
+            let take = Arc::clone(&take);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                for call in 0..CALLS_PER_THREAD {
+                    black_box(take(thread * KEYS_PER_THREAD + call % KEYS_PER_THREAD));
+                }
+            })
+        })
+        .collect();
+    barrier.wait();
+    let start = Instant::now();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, threads: usize, elapsed: Duration) {
+    let calls = CALLS_PER_THREAD * threads as u64;
+    let per_sec = calls as f64 / elapsed.as_secs_f64();
+    println!("{name:>8} threads={threads:<3} {elapsed:>12.2?} {per_sec:>14.0} calls/s");
+}
+
+fn main() {
+    let builder = TokenBucketBuilder::new().rate(1_000, Duration::from_secs(1));
+    let max_threads = thread::available_parallelism().map_or(4, |n| n.get());
+    let mut threads = 1;
+    while threads <= max_threads {
+        let keyed = Mutex::new(KeyedTokenBuckets::new(builder.clone()).unwrap());
+        let elapsed = run(threads, move |key| {
+            keyed.lock().unwrap().try_take(&key).is_ok()
+        });
+        report("mutex", threads, elapsed);
+
+        let sharded = ShardedTokenBuckets::new(builder.clone()).unwrap();
+        let elapsed = run(threads, move |key| sharded.try_take(&key).is_ok());
+        report("sharded", threads, elapsed);
+
+        threads *= 2;
+    }
+}
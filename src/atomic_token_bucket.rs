@@ -0,0 +1,161 @@
+use std::cmp;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::TokenBucketError;
+
+#[cfg(test)]
+use std::sync::Arc;
+#[cfg(test)]
+use std::thread;
+
+/// Same algorithm as TokenBucket, shared through &self.
+/// Instants are stored as nanos since base shifted by max_refresh_nanos,
+/// so the full bucket point never goes below zero.
+#[derive(Debug)]
+pub struct AtomicTokenBucket<C = SystemClock> {
+    base: Instant,
+    last_refreshed: AtomicU64,
+    max_refresh_nanos: u64,
+    refresh_interval_nanos: u64,
+    clock: C,
+}
+impl AtomicTokenBucket {
+    pub fn new(
+        refresh_interval: Duration,
+        max_capacity: u64,
+        initial_capacity: u64,
+    ) -> Result<AtomicTokenBucket, TokenBucketError> {
+        AtomicTokenBucket::with_clock(
+            refresh_interval,
+            max_capacity,
+            initial_capacity,
+            SystemClock,
+        )
+    }
+}
+impl<C: Clock> AtomicTokenBucket<C> {
+    pub fn with_clock(
+        refresh_interval: Duration,
+        max_capacity: u64,
+        initial_capacity: u64,
+        clock: C,
+    ) -> Result<AtomicTokenBucket<C>, TokenBucketError> {
+        if refresh_interval.is_zero() {
+            return Err(TokenBucketError::InvalidInterval);
+        }
+        if max_capacity == 0 {
+            return Err(TokenBucketError::InvalidCapacity);
+        }
+
+        let refresh_interval_nanos = u64::try_from(refresh_interval.as_nanos())
+            .map_err(|_| TokenBucketError::CapacityOverflow)?;
+        let max_refresh_nanos = refresh_interval_nanos
+            .checked_mul(max_capacity)
+            .ok_or(TokenBucketError::CapacityOverflow)?;
+        let current_tokens_count = cmp::min(max_capacity, initial_capacity);
+        let last_refreshed = max_refresh_nanos - refresh_interval_nanos * current_tokens_count;
+
+        Ok(AtomicTokenBucket {
+            base: clock.now(),
+            last_refreshed: AtomicU64::new(last_refreshed),
+            max_refresh_nanos,
+            refresh_interval_nanos,
+            clock,
+        })
+    }
+
+    fn now_nanos(&self) -> Result<u64, TokenBucketError> {
+        let elapsed = self.clock.now().saturating_duration_since(self.base);
+        u64::try_from(elapsed.as_nanos())
+            .ok()
+            .and_then(|elapsed| elapsed.checked_add(self.max_refresh_nanos))
+            .ok_or(TokenBucketError::CapacityOverflow)
+    }
+
+    pub fn try_take(&self) -> Result<(), TokenBucketError> {
+        self.try_take_n(1)
+    }
+
+    /// Takes all n tokens or none of them
+    pub fn try_take_n(&self, n: u64) -> Result<(), TokenBucketError> {
+        let refresh_nanos = self
+            .refresh_interval_nanos
+            .checked_mul(n)
+            .filter(|refresh_nanos| *refresh_nanos <= self.max_refresh_nanos)
+            .ok_or(TokenBucketError::CapacityOverflow)?;
+        let now = self.now_nanos()?;
+        let mut last_refreshed = self.last_refreshed.load(Ordering::Acquire);
+        loop {
+            let effective_last_refreshed = cmp::max(last_refreshed, now - self.max_refresh_nanos);
+            let new_last_refreshed = effective_last_refreshed + refresh_nanos;
+            if new_last_refreshed > now {
+                return Err(TokenBucketError::InsufficientTokens {
+                    retry_after: Duration::from_nanos(new_last_refreshed - now),
+                });
+            }
+            match self.last_refreshed.compare_exchange_weak(
+                last_refreshed,
+                new_last_refreshed,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(actual) => last_refreshed = actual,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_try_take {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn initializes_with_proper_tokens() {
+        let tb = AtomicTokenBucket::new(Duration::from_millis(1000), 1, 2).unwrap();
+        assert!(tb.try_take().is_ok());
+        assert!(tb.try_take().is_err());
+    }
+
+    #[test]
+    fn can_take_generated_tokens() {
+        let clock = MockClock::new();
+        let tb =
+            AtomicTokenBucket::with_clock(Duration::from_millis(100), 2, 1, clock.clone()).unwrap();
+        assert!(tb.try_take().is_ok());
+        assert!(tb.try_take().is_err());
+        clock.advance(Duration::from_millis(100));
+        assert!(tb.try_take().is_ok());
+        assert!(tb.try_take().is_err());
+    }
+
+    #[test]
+    fn refill_stops_at_max_capacity() {
+        let clock = MockClock::new();
+        let tb =
+            AtomicTokenBucket::with_clock(Duration::from_millis(10), 3, 0, clock.clone()).unwrap();
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(tb.try_take_n(4), Err(TokenBucketError::CapacityOverflow));
+        assert!(tb.try_take_n(3).is_ok());
+        assert!(tb.try_take().is_err());
+    }
+
+    #[test]
+    fn threads_share_one_limit() {
+        let clock = MockClock::new();
+        let tb = Arc::new(
+            AtomicTokenBucket::with_clock(Duration::from_millis(10), 1000, 1000, clock).unwrap(),
+        );
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let tb = Arc::clone(&tb);
+                thread::spawn(move || (0..500).filter(|_| tb.try_take().is_ok()).count())
+            })
+            .collect();
+        let taken: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(taken, 1000);
+    }
+}
@@ -0,0 +1,176 @@
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::TokenBucketError;
+use crate::token_bucket::{duration_from_nanos, TokenBucket};
+
+/// Named alternative to TokenBucket::new.
+/// Burst defaults to the tokens given to rate and the bucket starts full.
+#[derive(Clone, Debug)]
+pub struct TokenBucketBuilder<C = SystemClock> {
+    rate: Option<(u64, Duration)>,
+    burst: Option<u64>,
+    initial: Option<u64>,
+    clock: C,
+}
+impl TokenBucketBuilder {
+    pub fn new() -> TokenBucketBuilder {
+        TokenBucketBuilder {
+            rate: None,
+            burst: None,
+            initial: None,
+            clock: SystemClock,
+        }
+    }
+}
+impl Default for TokenBucketBuilder {
+    fn default() -> TokenBucketBuilder {
+        TokenBucketBuilder::new()
+    }
+}
+impl<C: Clock + Clone> TokenBucketBuilder<C> {
+    /// Refills tokens evenly spread over per
+    pub fn rate(mut self, tokens: u64, per: Duration) -> Self {
+        self.rate = Some((tokens, per));
+        self
+    }
+
+    pub fn burst(mut self, n: u64) -> Self {
+        self.burst = Some(n);
+        self
+    }
+
+    pub fn initial(mut self, n: u64) -> Self {
+        self.initial = Some(n);
+        self
+    }
+
+    pub fn start_full(mut self) -> Self {
+        self.initial = None;
+        self
+    }
+
+    pub fn start_empty(self) -> Self {
+        self.initial(0)
+    }
+
+    pub fn clock<D: Clock + Clone>(self, clock: D) -> TokenBucketBuilder<D> {
+        TokenBucketBuilder {
+            rate: self.rate,
+            burst: self.burst,
+            initial: self.initial,
+            clock,
+        }
+    }
+
+    pub fn build(&self) -> Result<TokenBucket<C>, TokenBucketError> {
+        let (tokens, per) = self.rate.ok_or(TokenBucketError::InvalidInterval)?;
+        let refresh_interval = interval_for_rate(tokens, per)?;
+        let burst = self.burst.unwrap_or(tokens);
+        let initial = self.initial.unwrap_or(burst);
+        if initial > burst {
+            return Err(TokenBucketError::InitialExceedsCapacity);
+        }
+        TokenBucket::with_clock(refresh_interval, burst, initial, self.clock.clone())
+    }
+}
+
+/// Rounds down, so the rate is never slower than asked for
+pub(crate) fn interval_for_rate(tokens: u64, per: Duration) -> Result<Duration, TokenBucketError> {
+    per.as_nanos()
+        .checked_div(u128::from(tokens))
+        .filter(|nanos| *nanos > 0)
+        .and_then(duration_from_nanos)
+        .ok_or(TokenBucketError::InvalidInterval)
+}
+
+#[cfg(test)]
+mod test_build {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn starts_full_with_burst_of_rate() {
+        let clock = MockClock::new();
+        let mut tb = TokenBucketBuilder::new()
+            .rate(10, Duration::from_secs(1))
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        assert_eq!(tb.try_take_n(10), Ok(()));
+        assert!(tb.try_take().is_err());
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(tb.try_take(), Ok(()));
+    }
+
+    #[test]
+    fn start_empty_with_burst() {
+        let clock = MockClock::new();
+        let mut tb = TokenBucket::builder()
+            .rate(1, Duration::from_millis(10))
+            .burst(5)
+            .start_empty()
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        assert!(tb.try_take().is_err());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(tb.try_take_n(5), Ok(()));
+        assert!(tb.try_take().is_err());
+    }
+
+    #[test]
+    fn initial_tokens() {
+        let mut tb = TokenBucket::builder()
+            .rate(1, Duration::from_secs(1))
+            .burst(5)
+            .initial(2)
+            .build()
+            .unwrap();
+        assert_eq!(tb.try_take_n(2), Ok(()));
+        assert!(tb.try_take().is_err());
+    }
+
+    #[test]
+    fn requires_rate() {
+        let err = TokenBucket::builder().burst(5).build().unwrap_err();
+        assert_eq!(err, TokenBucketError::InvalidInterval);
+    }
+
+    #[test]
+    fn rejects_unrepresentable_rate() {
+        let builder = TokenBucket::builder().rate(0, Duration::from_secs(1));
+        assert_eq!(
+            builder.build().unwrap_err(),
+            TokenBucketError::InvalidInterval
+        );
+        let builder = TokenBucket::builder().rate(10, Duration::from_nanos(1));
+        assert_eq!(
+            builder.build().unwrap_err(),
+            TokenBucketError::InvalidInterval
+        );
+    }
+
+    #[test]
+    fn rejects_zero_burst() {
+        let builder = TokenBucket::builder()
+            .rate(1, Duration::from_secs(1))
+            .burst(0);
+        assert_eq!(
+            builder.build().unwrap_err(),
+            TokenBucketError::InvalidCapacity
+        );
+    }
+
+    #[test]
+    fn rejects_initial_over_burst() {
+        let builder = TokenBucket::builder()
+            .rate(1, Duration::from_secs(1))
+            .burst(2)
+            .initial(3);
+        assert_eq!(
+            builder.build().unwrap_err(),
+            TokenBucketError::InitialExceedsCapacity
+        );
+    }
+}
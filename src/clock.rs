@@ -0,0 +1,152 @@
+use std::future::{self, Future};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::delay;
+
+/// Source of time for the buckets
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time for state shared with other processes
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    /// Same as sleep without blocking the executor
+    fn sleep_async(&self, duration: Duration) -> impl Future<Output = ()> + Send {
+        delay::sleep(duration)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Only moves when advanced, sleeping advances it instead of blocking.
+/// Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    start: Instant,
+    start_system_time: SystemTime,
+    elapsed_nanos: Arc<AtomicU64>,
+}
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            start: Instant::now(),
+            start_system_time: SystemTime::now(),
+            elapsed_nanos: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Panics rather than wrap once the total passes u64::MAX nanoseconds, about 584 years
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed_nanos
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |elapsed| {
+                u64::try_from(duration.as_nanos())
+                    .ok()
+                    .and_then(|nanos| elapsed.checked_add(nanos))
+            })
+            .expect("MockClock can't advance past u64::MAX nanoseconds");
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_nanos.load(Ordering::SeqCst))
+    }
+}
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.start_system_time + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+
+    fn sleep_async(&self, duration: Duration) -> impl Future<Output = ()> + Send {
+        self.advance(duration);
+        future::ready(())
+    }
+}
+
+#[cfg(test)]
+mod test_mock_clock {
+    use super::*;
+    use std::panic;
+
+    #[test]
+    fn only_moves_when_advanced() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(clock.now() - start, Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn sleep_advances_instead_of_blocking() {
+        let clock = MockClock::new();
+        let start = Instant::now();
+        clock.sleep(Duration::from_secs(60));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(clock.elapsed(), Duration::from_secs(60));
+    }
+
+    #[test]
+    #[should_panic(expected = "MockClock can't advance past u64::MAX nanoseconds")]
+    fn never_wraps_around() {
+        let clock = MockClock::new();
+        clock.advance(Duration::from_nanos(u64::MAX));
+        clock.advance(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn rejects_advances_it_cannot_count() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let advanced = panic::catch_unwind(|| clock.advance(Duration::MAX));
+        assert!(advanced.is_err());
+        assert_eq!(clock.now(), start);
+    }
+
+    #[test]
+    fn clones_share_time() {
+        let clock = MockClock::new();
+        let other = clock.clone();
+        other.advance(Duration::from_millis(10));
+        assert_eq!(clock.now(), other.now());
+        assert_eq!(clock.system_time(), other.system_time());
+    }
+
+    #[test]
+    fn system_time_moves_with_now() {
+        let clock = MockClock::new();
+        let start = clock.system_time();
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(
+            clock.system_time().duration_since(start).unwrap(),
+            Duration::from_secs(5)
+        );
+    }
+}
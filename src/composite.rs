@@ -0,0 +1,236 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::{NotUntil, TokenBucketError};
+use crate::limiter::{LimiterState, RateLimiter};
+use crate::token_bucket::TokenBucket;
+
+/// Level that held the request back the longest
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LevelDenied {
+    pub level: usize,
+    pub name: String,
+    pub not_until: NotUntil,
+}
+
+impl fmt::Display for LevelDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} limit: {}", self.name, self.not_until)
+    }
+}
+
+impl Error for LevelDenied {}
+
+impl From<LevelDenied> for NotUntil {
+    fn from(denied: LevelDenied) -> NotUntil {
+        denied.not_until
+    }
+}
+
+/// Nested buckets that are all taken from or none are, e.g. global then tenant then user
+pub struct CompositeLimiter<C = SystemClock> {
+    levels: Vec<(String, TokenBucket<C>)>,
+}
+impl<C: Clock> CompositeLimiter<C> {
+    pub fn new(name: impl Into<String>, bucket: TokenBucket<C>) -> CompositeLimiter<C> {
+        CompositeLimiter {
+            levels: vec![(name.into(), bucket)],
+        }
+    }
+
+    pub fn with_level(mut self, name: impl Into<String>, bucket: TokenBucket<C>) -> Self {
+        self.levels.push((name.into(), bucket));
+        self
+    }
+
+    pub fn level(&self, name: &str) -> Option<&TokenBucket<C>> {
+        self.levels
+            .iter()
+            .find(|(level_name, _)| level_name == name)
+            .map(|(_, bucket)| bucket)
+    }
+
+    /// For reconfiguring one level at runtime
+    pub fn level_mut(&mut self, name: &str) -> Option<&mut TokenBucket<C>> {
+        self.levels
+            .iter_mut()
+            .find(|(level_name, _)| level_name == name)
+            .map(|(_, bucket)| bucket)
+    }
+
+    /// In the order they were added
+    pub fn levels(&self) -> impl Iterator<Item = (&str, &TokenBucket<C>)> {
+        self.levels
+            .iter()
+            .map(|(name, bucket)| (name.as_str(), bucket))
+    }
+
+    pub fn check(&mut self) -> Result<Result<(), LevelDenied>, TokenBucketError> {
+        self.check_n(1)
+    }
+
+    /// Checks every level before taking from any, outer error when a level can never fit n
+    pub fn check_n(&mut self, n: u64) -> Result<Result<(), LevelDenied>, TokenBucketError> {
+        let mut new_last_refreshed = Vec::with_capacity(self.levels.len());
+        let mut denied: Option<(usize, NotUntil)> = None;
+        for (level, (_, bucket)) in self.levels.iter().enumerate() {
+            match bucket.peek_n(n)? {
+                Ok(time) => new_last_refreshed.push(time),
+                Err(not_until) => {
+                    if denied.is_none_or(|(_, longest)| not_until.wait > longest.wait) {
+                        denied = Some((level, not_until));
+                    }
+                }
+            }
+        }
+        if let Some((level, not_until)) = denied {
+            return Ok(Err(LevelDenied {
+                level,
+                name: self.levels[level].0.clone(),
+                not_until,
+            }));
+        }
+        for ((_, bucket), time) in self.levels.iter_mut().zip(new_last_refreshed) {
+            bucket.commit(time);
+        }
+        Ok(Ok(()))
+    }
+}
+impl<C: Clock> RateLimiter for CompositeLimiter<C> {
+    fn check_n(&mut self, n: u64) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        Ok(CompositeLimiter::check_n(self, n)?.map_err(NotUntil::from))
+    }
+
+    fn wait_n(&mut self, n: u64) -> Result<(), TokenBucketError> {
+        while let Err(denied) = CompositeLimiter::check_n(self, n)? {
+            self.levels[denied.level]
+                .1
+                .clock()
+                .sleep(denied.not_until.wait);
+        }
+        Ok(())
+    }
+
+    /// The tightest level
+    fn state(&self) -> LimiterState {
+        let min = |field: fn(&TokenBucket<C>) -> u64| {
+            self.levels
+                .iter()
+                .map(|(_, bucket)| field(bucket))
+                .min()
+                .expect("created with one level")
+        };
+        LimiterState {
+            available: min(TokenBucket::available),
+            capacity: min(TokenBucket::capacity),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_check_n {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::time::Duration;
+
+    fn nested(clock: &MockClock) -> CompositeLimiter<MockClock> {
+        let bucket = |interval_ms, capacity| {
+            TokenBucket::with_clock(
+                Duration::from_millis(interval_ms),
+                capacity,
+                capacity,
+                clock.clone(),
+            )
+            .unwrap()
+        };
+        CompositeLimiter::new("global", bucket(10, 10))
+            .with_level("tenant", bucket(100, 4))
+            .with_level("user", bucket(40, 2))
+    }
+
+    #[test]
+    fn takes_from_every_level() {
+        let clock = MockClock::new();
+        let mut limiter = nested(&clock);
+        assert_eq!(limiter.check_n(2), Ok(Ok(())));
+        assert_eq!(limiter.level("global").unwrap().available(), 8);
+        assert_eq!(limiter.level("tenant").unwrap().available(), 2);
+        assert_eq!(limiter.level("user").unwrap().available(), 0);
+    }
+
+    #[test]
+    fn denied_request_takes_from_no_level() {
+        let clock = MockClock::new();
+        let mut limiter = nested(&clock);
+        limiter.check_n(2).unwrap().unwrap();
+        let denied = limiter.check().unwrap().unwrap_err();
+        assert_eq!(denied.level, 2);
+        assert_eq!(denied.name, "user");
+        assert_eq!(denied.not_until.wait, Duration::from_millis(40));
+        assert_eq!(limiter.level("global").unwrap().available(), 8);
+        assert_eq!(limiter.level("tenant").unwrap().available(), 2);
+    }
+
+    #[test]
+    fn reports_the_longest_wait() {
+        let clock = MockClock::new();
+        let mut limiter = nested(&clock);
+        limiter.check_n(2).unwrap().unwrap();
+        clock.advance(Duration::from_millis(100));
+        limiter.check_n(2).unwrap().unwrap();
+        let denied = limiter.check_n(2).unwrap().unwrap_err();
+        assert_eq!(denied.name, "tenant");
+        assert_eq!(denied.not_until.wait, Duration::from_millis(100));
+        assert_eq!(
+            denied.to_string(),
+            "tenant limit: not enough tokens until 100ms from now"
+        );
+    }
+
+    #[test]
+    fn rejects_more_than_any_level_can_hold() {
+        let clock = MockClock::new();
+        let mut limiter = nested(&clock);
+        assert_eq!(limiter.check_n(3), Err(TokenBucketError::CapacityOverflow));
+        assert_eq!(limiter.level("global").unwrap().available(), 10);
+    }
+
+    #[test]
+    fn levels_can_be_reconfigured() {
+        let clock = MockClock::new();
+        let mut limiter = nested(&clock);
+        limiter.level_mut("user").unwrap().set_capacity(4).unwrap();
+        clock.advance(Duration::from_millis(40));
+        assert_eq!(limiter.check_n(3), Ok(Ok(())));
+        let names: Vec<&str> = limiter.levels().map(|(name, _)| name).collect();
+        assert_eq!(names, ["global", "tenant", "user"]);
+    }
+}
+
+#[cfg(test)]
+mod test_rate_limiter {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::time::Duration;
+
+    #[test]
+    fn state_and_wait_follow_the_tightest_level() {
+        let clock = MockClock::new();
+        let global =
+            TokenBucket::with_clock(Duration::from_millis(10), 10, 10, clock.clone()).unwrap();
+        let user = TokenBucket::with_clock(Duration::from_millis(50), 2, 1, clock.clone()).unwrap();
+        let mut limiter: Box<dyn RateLimiter> =
+            Box::new(CompositeLimiter::new("global", global).with_level("user", user));
+        assert_eq!(
+            limiter.state(),
+            LimiterState {
+                available: 1,
+                capacity: 2
+            }
+        );
+        assert_eq!(limiter.wait_n(2), Ok(()));
+        assert_eq!(clock.elapsed(), Duration::from_millis(50));
+        assert_eq!(limiter.state().available, 0);
+    }
+}
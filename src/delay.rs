@@ -0,0 +1,259 @@
+use std::time::Duration;
+
+#[cfg(not(feature = "tokio"))]
+use std::cmp::Reverse;
+#[cfg(not(feature = "tokio"))]
+use std::collections::{BinaryHeap, HashMap};
+#[cfg(not(feature = "tokio"))]
+use std::future::Future;
+#[cfg(not(feature = "tokio"))]
+use std::pin::Pin;
+#[cfg(not(feature = "tokio"))]
+use std::sync::{Condvar, Mutex, MutexGuard, OnceLock, PoisonError};
+#[cfg(not(feature = "tokio"))]
+use std::task::{Context, Poll, Waker};
+#[cfg(not(feature = "tokio"))]
+use std::thread;
+#[cfg(not(feature = "tokio"))]
+use std::time::Instant;
+
+/// Resolves once duration has passed without blocking the executor.
+/// Uses the tokio timer with the tokio feature. Without it a single background thread
+/// wakes every pending sleep, a fallback for light use rather than a general async timer.
+/// Durations past what an Instant can hold never resolve.
+pub async fn sleep(duration: Duration) {
+    #[cfg(feature = "tokio")]
+    tokio::time::sleep(duration).await;
+    #[cfg(not(feature = "tokio"))]
+    Delay::new(duration).await;
+}
+
+#[cfg(not(feature = "tokio"))]
+struct Delay {
+    // None when the deadline can't be represented
+    deadline: Option<Instant>,
+    timer_id: Option<u64>,
+}
+#[cfg(not(feature = "tokio"))]
+impl Delay {
+    fn new(duration: Duration) -> Delay {
+        Delay {
+            deadline: Instant::now().checked_add(duration),
+            timer_id: None,
+        }
+    }
+}
+#[cfg(not(feature = "tokio"))]
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let Some(deadline) = self.deadline else {
+            return Poll::Pending;
+        };
+        if Instant::now() >= deadline {
+            return Poll::Ready(());
+        }
+        let timer_id = Timer::get().wake_at(self.timer_id, deadline, cx.waker());
+        self.timer_id = Some(timer_id);
+        Poll::Pending
+    }
+}
+#[cfg(not(feature = "tokio"))]
+impl Drop for Delay {
+    fn drop(&mut self) {
+        if let Some(timer_id) = self.timer_id {
+            Timer::get().lock().wakers.remove(&timer_id);
+        }
+    }
+}
+
+// Wakes delays in deadline order from one thread, started by the first pending delay
+#[cfg(not(feature = "tokio"))]
+struct Timer {
+    state: Mutex<TimerState>,
+    changed: Condvar,
+}
+#[cfg(not(feature = "tokio"))]
+#[derive(Default)]
+struct TimerState {
+    deadlines: BinaryHeap<Reverse<(Instant, u64)>>,
+    wakers: HashMap<u64, Waker>,
+    next_id: u64,
+}
+#[cfg(not(feature = "tokio"))]
+impl Timer {
+    fn get() -> &'static Timer {
+        static TIMER: OnceLock<&'static Timer> = OnceLock::new();
+        TIMER.get_or_init(|| {
+            let timer: &'static Timer = Box::leak(Box::new(Timer {
+                state: Mutex::new(TimerState::default()),
+                changed: Condvar::new(),
+            }));
+            thread::Builder::new()
+                .name("token-bucket-timer".to_owned())
+                .spawn(move || timer.run())
+                .expect("failed to spawn the timer thread");
+            timer
+        })
+    }
+
+    // Registers a new delay or updates the waker of a registered one
+    fn wake_at(&self, timer_id: Option<u64>, deadline: Instant, waker: &Waker) -> u64 {
+        let mut state = self.lock();
+        let timer_id = match timer_id {
+            Some(timer_id) => timer_id,
+            None => {
+                let timer_id = state.next_id;
+                state.next_id += 1;
+                state.deadlines.push(Reverse((deadline, timer_id)));
+                self.changed.notify_one();
+                timer_id
+            }
+        };
+        match state.wakers.get_mut(&timer_id) {
+            Some(registered) => registered.clone_from(waker),
+            None => {
+                state.wakers.insert(timer_id, waker.clone());
+            }
+        }
+        timer_id
+    }
+
+    fn run(&self) {
+        let mut state = self.lock();
+        loop {
+            let now = Instant::now();
+            let mut due = Vec::new();
+            while let Some(&Reverse((deadline, timer_id))) = state.deadlines.peek() {
+                if deadline > now {
+                    break;
+                }
+                state.deadlines.pop();
+                due.extend(state.wakers.remove(&timer_id));
+            }
+            if !due.is_empty() {
+                drop(state);
+                due.into_iter().for_each(Waker::wake);
+                state = self.lock();
+                continue;
+            }
+            state = match state.deadlines.peek() {
+                Some(&Reverse((deadline, _))) => {
+                    self.changed
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TimerState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Only drives futures that don't need a tokio runtime
+#[cfg(test)]
+pub(crate) fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::pin::pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake};
+    use std::thread::{self, Thread};
+
+    struct ThreadWaker(Thread);
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Arc::new(ThreadWaker(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "tokio")))]
+mod test_sleep {
+    use super::*;
+
+    #[test]
+    fn resolves_after_duration() {
+        let now = Instant::now();
+        block_on(sleep(Duration::from_millis(20)));
+        assert!(now.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn zero_duration_is_ready() {
+        let now = Instant::now();
+        block_on(sleep(Duration::ZERO));
+        assert!(now.elapsed() < Duration::from_millis(5));
+    }
+
+    #[test]
+    fn one_timer_wakes_concurrent_sleeps() {
+        let now = Instant::now();
+        let sleepers: Vec<_> = [30, 10, 20]
+            .into_iter()
+            .map(|millis| {
+                thread::spawn(move || {
+                    block_on(sleep(Duration::from_millis(millis)));
+                    now.elapsed()
+                })
+            })
+            .collect();
+        let woke: Vec<Duration> = sleepers
+            .into_iter()
+            .map(|sleeper| sleeper.join().unwrap())
+            .collect();
+        for (woke, millis) in woke.into_iter().zip([30, 10, 20]) {
+            assert!(woke >= Duration::from_millis(millis));
+        }
+    }
+
+    #[test]
+    fn unrepresentable_deadline_never_resolves() {
+        let waker = Waker::noop();
+        let mut cx = Context::from_waker(waker);
+        let mut delay = Delay::new(Duration::MAX);
+        assert_eq!(Pin::new(&mut delay).poll(&mut cx), Poll::Pending);
+        assert_eq!(delay.timer_id, None);
+    }
+
+    #[test]
+    fn dropped_sleeps_are_forgotten() {
+        let waker = Waker::noop();
+        let mut cx = Context::from_waker(waker);
+        let mut delay = Delay::new(Duration::from_secs(60));
+        assert_eq!(Pin::new(&mut delay).poll(&mut cx), Poll::Pending);
+        let timer_id = delay.timer_id.unwrap();
+        assert!(Timer::get().lock().wakers.contains_key(&timer_id));
+        drop(delay);
+        assert!(!Timer::get().lock().wakers.contains_key(&timer_id));
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod test_tokio_sleep {
+    use super::*;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn resolves_after_duration() {
+        let now = Instant::now();
+        sleep(Duration::from_millis(20)).await;
+        assert!(now.elapsed() >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,107 @@
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TokenBucketError {
+    /// Refresh interval was zero or couldn't be derived from the rate
+    InvalidInterval,
+    /// Max capacity was zero so no token could ever be taken
+    InvalidCapacity,
+    /// Initial tokens were more than max capacity
+    InitialExceedsCapacity,
+    /// More tokens were asked for than the bucket can hold or represent
+    CapacityOverflow,
+    /// The clock is too close to its origin to back-date the initial tokens
+    ClockUnderflow,
+    /// Not enough tokens yet
+    InsufficientTokens { retry_after: Duration },
+    /// A reservation was cancelled into a bucket it wasn't taken from
+    ForeignReservation,
+}
+
+impl fmt::Display for TokenBucketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenBucketError::InvalidInterval => write!(f, "refresh interval must be non-zero"),
+            TokenBucketError::InvalidCapacity => write!(f, "max capacity must be non-zero"),
+            TokenBucketError::InitialExceedsCapacity => {
+                write!(f, "initial tokens must not exceed max capacity")
+            }
+            TokenBucketError::CapacityOverflow => {
+                write!(f, "token count exceeds what the bucket can hold")
+            }
+            TokenBucketError::ClockUnderflow => {
+                write!(
+                    f,
+                    "clock cannot be moved back far enough for the initial tokens"
+                )
+            }
+            TokenBucketError::InsufficientTokens { retry_after } => {
+                write!(f, "not enough tokens, retry after {:?}", retry_after)
+            }
+            TokenBucketError::ForeignReservation => {
+                write!(f, "reservation belongs to another bucket")
+            }
+        }
+    }
+}
+
+impl Error for TokenBucketError {}
+
+/// Earliest time the requested tokens are available and how long that is from now
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NotUntil {
+    pub earliest: Instant,
+    pub wait: Duration,
+}
+
+impl fmt::Display for NotUntil {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "not enough tokens until {:?} from now", self.wait)
+    }
+}
+
+impl Error for NotUntil {}
+
+impl From<NotUntil> for TokenBucketError {
+    fn from(not_until: NotUntil) -> TokenBucketError {
+        TokenBucketError::InsufficientTokens {
+            retry_after: not_until.wait,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_display {
+    use super::*;
+
+    #[test]
+    fn includes_retry_after() {
+        let err = TokenBucketError::InsufficientTokens {
+            retry_after: Duration::from_millis(15),
+        };
+        assert_eq!(err.to_string(), "not enough tokens, retry after 15ms");
+    }
+
+    #[test]
+    fn is_std_error() {
+        let err: Box<dyn Error> = Box::new(TokenBucketError::InvalidInterval);
+        assert_eq!(err.to_string(), "refresh interval must be non-zero");
+    }
+
+    #[test]
+    fn not_until_converts_to_insufficient_tokens() {
+        let not_until = NotUntil {
+            earliest: Instant::now(),
+            wait: Duration::from_millis(3),
+        };
+        assert_eq!(
+            TokenBucketError::from(not_until),
+            TokenBucketError::InsufficientTokens {
+                retry_after: Duration::from_millis(3)
+            }
+        );
+    }
+}
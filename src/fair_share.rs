@@ -0,0 +1,475 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::{NotUntil, TokenBucketError};
+use crate::token_bucket::{duration_from_nanos, TokenBucket};
+
+/// Tokens a consumer has taken against its share and how often it was turned away
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConsumerStats {
+    /// Tokens taken, borrowed ones included
+    pub taken: u64,
+    /// Tokens of taken that came out of another consumer's share
+    pub borrowed: u64,
+    /// Requests denied, however many tokens they asked for
+    pub denied: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FairShareError {
+    /// The consumer was never registered
+    UnknownConsumer,
+    Bucket(TokenBucketError),
+}
+
+impl fmt::Display for FairShareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FairShareError::UnknownConsumer => write!(f, "consumer is not registered"),
+            FairShareError::Bucket(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for FairShareError {}
+
+impl From<TokenBucketError> for FairShareError {
+    fn from(err: TokenBucketError) -> FairShareError {
+        FairShareError::Bucket(err)
+    }
+}
+
+impl From<NotUntil> for FairShareError {
+    fn from(not_until: NotUntil) -> FairShareError {
+        FairShareError::Bucket(not_until.into())
+    }
+}
+
+struct Consumer<C> {
+    weight: u64,
+    bucket: TokenBucket<C>,
+    stats: ConsumerStats,
+}
+impl<C: Clock> Consumer<C> {
+    // Lenders keep at least half their burst for themselves
+    fn lends(&self, n: u64) -> bool {
+        self.bucket
+            .available()
+            .checked_sub(n)
+            .is_some_and(|left| left >= self.bucket.capacity() / 2)
+    }
+}
+
+/// Splits one bucket's refill and burst between consumers by weight.
+/// A consumer out of tokens borrows from an idle one before being denied.
+/// Tokens only come from refill, never from registering or reweighting.
+pub struct FairShareLimiter<K, C = SystemClock> {
+    refill_interval: Duration,
+    capacity: u64,
+    clock: C,
+    consumers: HashMap<K, Consumer<C>>,
+    // Tokens no consumer holds, from the bucket, shrunk shares and unregistered consumers
+    unclaimed: u64,
+}
+impl<K: Hash + Eq, C: Clock + Clone> FairShareLimiter<K, C> {
+    /// Shares the rate and capacity of bucket, its current tokens go to the first consumers
+    pub fn new(bucket: TokenBucket<C>) -> FairShareLimiter<K, C> {
+        FairShareLimiter {
+            refill_interval: bucket.refill_interval(),
+            capacity: bucket.capacity(),
+            clock: bucket.clock().clone(),
+            consumers: HashMap::new(),
+            unclaimed: bucket.available(),
+        }
+    }
+
+    /// New consumers start with what's unclaimed up to their burst, everyone's share
+    /// is recomputed keeping their current tokens clamped to the new burst.
+    /// Fails when any share would hold less than one token.
+    pub fn register(&mut self, key: K, weight: u64) -> Result<(), TokenBucketError> {
+        if weight == 0 {
+            return Err(TokenBucketError::InvalidCapacity);
+        }
+        let total_weight = self
+            .consumers
+            .iter()
+            .filter(|(consumer, _)| **consumer != key)
+            .map(|(_, consumer)| u128::from(consumer.weight))
+            .sum::<u128>()
+            + u128::from(weight);
+        for consumer in self.consumers.values() {
+            self.share(consumer.weight, total_weight)?;
+        }
+        let (refill_interval, burst) = self.share(weight, total_weight)?;
+        if let Some(consumer) = self.consumers.get_mut(&key) {
+            consumer.weight = weight;
+            return self.rebalance(total_weight);
+        }
+        self.rebalance(total_weight)?;
+        let tokens = cmp::min(burst, self.unclaimed);
+        let bucket = TokenBucket::with_clock(refill_interval, burst, tokens, self.clock.clone())?;
+        self.unclaimed -= tokens;
+        self.consumers.insert(
+            key,
+            Consumer {
+                weight,
+                bucket,
+                stats: ConsumerStats::default(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Hands the consumer's share back to the others
+    pub fn unregister(&mut self, key: &K) -> Option<ConsumerStats> {
+        let consumer = self.consumers.remove(key)?;
+        self.unclaim(consumer.bucket.available());
+        let total_weight = self
+            .consumers
+            .values()
+            .map(|consumer| u128::from(consumer.weight))
+            .sum();
+        self.rebalance(total_weight)
+            .expect("remaining shares only grow within the shared bucket");
+        Some(consumer.stats)
+    }
+
+    pub fn try_take(&mut self, key: &K) -> Result<(), FairShareError> {
+        self.try_take_n(key, 1)
+    }
+
+    pub fn try_take_n(&mut self, key: &K, n: u64) -> Result<(), FairShareError> {
+        Ok(self.check_n(key, n)??)
+    }
+
+    pub fn check(&mut self, key: &K) -> Result<Result<(), NotUntil>, FairShareError> {
+        self.check_n(key, 1)
+    }
+
+    /// Takes from the consumer's own share, else from the idlest lender,
+    /// denied with the wait for the consumer's own share
+    pub fn check_n(&mut self, key: &K, n: u64) -> Result<Result<(), NotUntil>, FairShareError> {
+        let consumer = self
+            .consumers
+            .get_mut(key)
+            .ok_or(FairShareError::UnknownConsumer)?;
+        let denied = match consumer.bucket.check_n(n) {
+            Ok(Ok(())) => {
+                consumer.stats.taken += n;
+                return Ok(Ok(()));
+            }
+            denied => denied,
+        };
+        let lent = self
+            .consumers
+            .iter_mut()
+            .filter(|(lender, consumer)| *lender != key && consumer.lends(n))
+            .max_by_key(|(_, lender)| lender.bucket.available())
+            .is_some_and(|(_, lender)| lender.bucket.check_n(n) == Ok(Ok(())));
+        let stats = &mut self
+            .consumers
+            .get_mut(key)
+            .expect("consumer was found above")
+            .stats;
+        if lent {
+            stats.taken += n;
+            stats.borrowed += n;
+            return Ok(Ok(()));
+        }
+        stats.denied += 1;
+        Ok(denied?)
+    }
+
+    /// Tokens in the consumer's own share
+    pub fn available(&self, key: &K) -> Option<u64> {
+        self.consumers
+            .get(key)
+            .map(|consumer| consumer.bucket.available())
+    }
+
+    pub fn stats(&self, key: &K) -> Option<ConsumerStats> {
+        self.consumers.get(key).map(|consumer| consumer.stats)
+    }
+
+    pub fn len(&self) -> usize {
+        self.consumers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.consumers.is_empty()
+    }
+
+    // Tokens clamped off a shrunk share are unclaimed rather than lost
+    fn rebalance(&mut self, total_weight: u128) -> Result<(), TokenBucketError> {
+        let mut clamped = 0;
+        for consumer in self.consumers.values_mut() {
+            let (refill_interval, burst) = share(
+                self.refill_interval,
+                self.capacity,
+                consumer.weight,
+                total_weight,
+            )?;
+            let available = consumer.bucket.available();
+            consumer.bucket.set_capacity(burst)?;
+            consumer.bucket.set_refill_interval(refill_interval)?;
+            clamped += available.saturating_sub(consumer.bucket.available());
+        }
+        self.unclaim(clamped);
+        Ok(())
+    }
+
+    fn unclaim(&mut self, tokens: u64) {
+        self.unclaimed = cmp::min(self.capacity, self.unclaimed.saturating_add(tokens));
+    }
+
+    fn share(&self, weight: u64, total_weight: u128) -> Result<(Duration, u64), TokenBucketError> {
+        share(self.refill_interval, self.capacity, weight, total_weight)
+    }
+}
+
+// A weight of w out of W refills at most w/W as fast and holds at most w/W of the burst,
+// so the shares together never exceed the bucket. A share too small for one token is rejected.
+fn share(
+    refill_interval: Duration,
+    capacity: u64,
+    weight: u64,
+    total_weight: u128,
+) -> Result<(Duration, u64), TokenBucketError> {
+    let refill_interval = refill_interval
+        .as_nanos()
+        .checked_mul(total_weight)
+        .and_then(|nanos| duration_from_nanos(nanos.div_ceil(u128::from(weight))))
+        .ok_or(TokenBucketError::CapacityOverflow)?;
+    let burst = u128::from(capacity) * u128::from(weight) / total_weight;
+    if burst == 0 {
+        return Err(TokenBucketError::InvalidCapacity);
+    }
+    Ok((refill_interval, burst as u64))
+}
+
+#[cfg(test)]
+mod test_register {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn limiter(clock: &MockClock) -> FairShareLimiter<&'static str, MockClock> {
+        let bucket =
+            TokenBucket::with_clock(Duration::from_millis(10), 30, 30, clock.clone()).unwrap();
+        FairShareLimiter::new(bucket)
+    }
+
+    #[test]
+    fn splits_rate_and_burst_by_weight() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        limiter.register("a", 2).unwrap();
+        limiter.register("b", 1).unwrap();
+        assert_eq!(limiter.available(&"a"), Some(20));
+        assert_eq!(limiter.available(&"b"), Some(10));
+        limiter.try_take_n(&"a", 20).unwrap();
+        limiter.try_take_n(&"b", 10).unwrap();
+        clock.advance(Duration::from_millis(30));
+        assert_eq!(limiter.available(&"a"), Some(2));
+        assert_eq!(limiter.available(&"b"), Some(1));
+    }
+
+    #[test]
+    fn new_consumers_shrink_existing_shares() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        limiter.register("a", 1).unwrap();
+        assert_eq!(limiter.available(&"a"), Some(30));
+        limiter.register("b", 2).unwrap();
+        assert_eq!(limiter.available(&"a"), Some(10));
+        limiter.register("a", 4).unwrap();
+        assert_eq!(limiter.len(), 2);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.available(&"a"), Some(20));
+        assert_eq!(limiter.available(&"b"), Some(10));
+    }
+
+    #[test]
+    fn unregister_returns_the_share() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        limiter.register("a", 1).unwrap();
+        limiter.register("b", 1).unwrap();
+        limiter.try_take(&"b").unwrap();
+        let stats = limiter.unregister(&"b").unwrap();
+        assert_eq!(stats.taken, 1);
+        assert_eq!(limiter.unregister(&"b"), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.available(&"a"), Some(30));
+    }
+
+    #[test]
+    fn registering_creates_no_tokens() {
+        let clock = MockClock::new();
+        let bucket =
+            TokenBucket::with_clock(Duration::from_secs(1), 30, 30, clock.clone()).unwrap();
+        let mut limiter = FairShareLimiter::new(bucket);
+        limiter.register("a", 1).unwrap();
+        let mut admitted = 0;
+        for _ in 0..5 {
+            limiter.register("b", 1).unwrap();
+            while limiter.try_take(&"b").is_ok() {
+                admitted += 1;
+            }
+            limiter.unregister(&"b");
+            clock.advance(Duration::from_secs(1));
+        }
+        while limiter.try_take(&"a").is_ok() {
+            admitted += 1;
+        }
+        // the bucket's 30 and a token a second
+        assert!(admitted <= 30 + 5, "admitted {}", admitted);
+    }
+
+    #[test]
+    fn shrunk_shares_go_to_new_consumers() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        limiter.register("a", 1).unwrap();
+        limiter.try_take_n(&"a", 20).unwrap();
+        limiter.register("b", 1).unwrap();
+        assert_eq!(limiter.available(&"a"), Some(10));
+        assert_eq!(limiter.available(&"b"), Some(0));
+        limiter.unregister(&"a");
+        limiter.register("c", 2).unwrap();
+        assert_eq!(limiter.available(&"c"), Some(10));
+    }
+
+    #[test]
+    fn rejects_shares_below_one_token() {
+        let clock = MockClock::new();
+        let bucket = TokenBucket::with_clock(Duration::from_secs(1), 2, 2, clock.clone()).unwrap();
+        let mut limiter = FairShareLimiter::new(bucket);
+        limiter.register("a", 1).unwrap();
+        limiter.register("b", 1).unwrap();
+        assert_eq!(
+            limiter.register("c", 1),
+            Err(TokenBucketError::InvalidCapacity)
+        );
+        assert_eq!(
+            limiter.register("a", 2),
+            Err(TokenBucketError::InvalidCapacity)
+        );
+        assert_eq!(limiter.len(), 2);
+        let admitted = ["a", "b", "a", "b"]
+            .iter()
+            .filter(|key| limiter.try_take(key).is_ok())
+            .count();
+        assert_eq!(admitted, 2);
+    }
+
+    #[test]
+    fn shares_together_stay_within_the_bucket() {
+        let clock = MockClock::new();
+        // a share's interval of 1.5ns must round up, not down to the bucket's own 1ns
+        let bucket = TokenBucket::with_clock(Duration::from_nanos(1), 30, 30, clock.clone());
+        let mut limiter = FairShareLimiter::new(bucket.unwrap());
+        limiter.register("a", 2).unwrap();
+        limiter.register("b", 1).unwrap();
+        let mut admitted = 0;
+        for _ in 0..100 {
+            for key in ["a", "b"] {
+                while limiter.try_take(&key).is_ok() {
+                    admitted += 1;
+                }
+            }
+            clock.advance(Duration::from_nanos(6));
+        }
+        // the bucket's burst plus one token a nanosecond
+        assert!(admitted <= 30 + 600, "admitted {}", admitted);
+    }
+
+    #[test]
+    fn rejects_zero_weight() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        assert_eq!(
+            limiter.register("a", 0),
+            Err(TokenBucketError::InvalidCapacity)
+        );
+        assert!(limiter.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod test_check_n {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn limiter(clock: &MockClock) -> FairShareLimiter<&'static str, MockClock> {
+        let bucket =
+            TokenBucket::with_clock(Duration::from_millis(10), 30, 30, clock.clone()).unwrap();
+        let mut limiter = FairShareLimiter::new(bucket);
+        limiter.register("noisy", 2).unwrap();
+        limiter.register("quiet", 1).unwrap();
+        limiter
+    }
+
+    #[test]
+    fn borrows_from_idle_consumers() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        assert_eq!(limiter.check_n(&"noisy", 20), Ok(Ok(())));
+        assert_eq!(limiter.check_n(&"noisy", 3), Ok(Ok(())));
+        assert_eq!(limiter.available(&"quiet"), Some(7));
+        assert_eq!(
+            limiter.stats(&"noisy"),
+            Some(ConsumerStats {
+                taken: 23,
+                borrowed: 3,
+                denied: 0
+            })
+        );
+    }
+
+    #[test]
+    fn busy_consumers_do_not_lend() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        limiter.try_take_n(&"quiet", 6).unwrap();
+        limiter.try_take_n(&"noisy", 20).unwrap();
+        let not_until = limiter.check(&"noisy").unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_millis(15));
+        assert_eq!(limiter.available(&"quiet"), Some(4));
+        assert_eq!(limiter.stats(&"noisy").unwrap().denied, 1);
+        assert_eq!(limiter.check_n(&"quiet", 4), Ok(Ok(())));
+    }
+
+    #[test]
+    fn lenders_keep_half_their_burst() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        assert_eq!(limiter.check_n(&"quiet", 10), Ok(Ok(())));
+        assert_eq!(limiter.check_n(&"quiet", 10), Ok(Ok(())));
+        assert_eq!(limiter.available(&"noisy"), Some(10));
+        let not_until = limiter.check(&"quiet").unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_millis(30));
+        assert_eq!(limiter.available(&"noisy"), Some(10));
+        assert_eq!(
+            limiter.check_n(&"quiet", 31),
+            Err(FairShareError::Bucket(TokenBucketError::CapacityOverflow))
+        );
+        assert_eq!(limiter.stats(&"quiet").unwrap().denied, 2);
+    }
+
+    #[test]
+    fn rejects_unknown_consumers() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        assert_eq!(
+            limiter.try_take(&"other"),
+            Err(FairShareError::UnknownConsumer)
+        );
+        assert_eq!(limiter.available(&"other"), None);
+        assert_eq!(limiter.stats(&"other"), None);
+    }
+}
@@ -0,0 +1,169 @@
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::{NotUntil, TokenBucketError};
+use crate::limiter::{LimiterState, RateLimiter};
+use crate::token_bucket::duration_from_nanos;
+
+/// At most limit tokens per window, windows start back to back from creation.
+/// The end of the current window must be representable, so huge windows are rejected.
+pub struct FixedWindowLimiter<C = SystemClock> {
+    limit: u64,
+    window: Duration,
+    window_start: Instant,
+    count: u64,
+    clock: C,
+}
+impl FixedWindowLimiter {
+    pub fn new(limit: u64, window: Duration) -> Result<FixedWindowLimiter, TokenBucketError> {
+        FixedWindowLimiter::with_clock(limit, window, SystemClock)
+    }
+}
+impl<C: Clock> FixedWindowLimiter<C> {
+    pub fn with_clock(
+        limit: u64,
+        window: Duration,
+        clock: C,
+    ) -> Result<FixedWindowLimiter<C>, TokenBucketError> {
+        if window.is_zero() {
+            return Err(TokenBucketError::InvalidInterval);
+        }
+        if limit == 0 {
+            return Err(TokenBucketError::InvalidCapacity);
+        }
+        Ok(FixedWindowLimiter {
+            limit,
+            window,
+            window_start: fits_windows(clock.now(), window, 1)?,
+            count: 0,
+            clock,
+        })
+    }
+
+    fn roll_window(&mut self, now: Instant) -> Result<(), TokenBucketError> {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        let windows = elapsed.as_nanos() / self.window.as_nanos();
+        if windows > 0 {
+            let skipped = duration_from_nanos(windows * self.window.as_nanos())
+                .expect("no more than the elapsed time");
+            self.window_start = fits_windows(self.window_start + skipped, self.window, 1)?;
+            self.count = 0;
+        }
+        Ok(())
+    }
+}
+impl<C: Clock> RateLimiter for FixedWindowLimiter<C> {
+    fn check_n(&mut self, n: u64) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        if n > self.limit {
+            return Err(TokenBucketError::CapacityOverflow);
+        }
+        let now = self.clock.now();
+        self.roll_window(now)?;
+        if n <= self.limit - self.count {
+            self.count += n;
+            return Ok(Ok(()));
+        }
+        let earliest = self.window_start + self.window;
+        Ok(Err(NotUntil {
+            earliest,
+            wait: earliest - now,
+        }))
+    }
+
+    fn wait_n(&mut self, n: u64) -> Result<(), TokenBucketError> {
+        while let Err(not_until) = self.check_n(n)? {
+            self.clock.sleep(not_until.wait);
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> LimiterState {
+        let window_ended = self.clock.now() >= self.window_start + self.window;
+        let count = if window_ended { 0 } else { self.count };
+        LimiterState {
+            available: self.limit - count,
+            capacity: self.limit,
+        }
+    }
+}
+
+// Passes start through if the given number of windows past it fit in an Instant
+pub(crate) fn fits_windows(
+    start: Instant,
+    window: Duration,
+    windows: u32,
+) -> Result<Instant, TokenBucketError> {
+    window
+        .checked_mul(windows)
+        .and_then(|windows| start.checked_add(windows))
+        .map(|_| start)
+        .ok_or(TokenBucketError::CapacityOverflow)
+}
+
+#[cfg(test)]
+mod test_check_n {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn limiter(clock: &MockClock) -> FixedWindowLimiter<MockClock> {
+        FixedWindowLimiter::with_clock(3, Duration::from_secs(1), clock.clone()).unwrap()
+    }
+
+    #[test]
+    fn allows_limit_per_window() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        assert_eq!(limiter.check_n(2), Ok(Ok(())));
+        assert_eq!(limiter.check(), Ok(Ok(())));
+        clock.advance(Duration::from_millis(400));
+        let not_until = limiter.check().unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_millis(600));
+        clock.advance(not_until.wait);
+        assert_eq!(limiter.check_n(3), Ok(Ok(())));
+    }
+
+    #[test]
+    fn windows_stay_aligned_after_idle() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        clock.advance(Duration::from_millis(2500));
+        assert_eq!(limiter.check_n(3), Ok(Ok(())));
+        let not_until = limiter.check().unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn rejects_more_than_limit() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        assert_eq!(limiter.check_n(4), Err(TokenBucketError::CapacityOverflow));
+    }
+
+    #[test]
+    fn counts_up_to_u64_max() {
+        let clock = MockClock::new();
+        let mut limiter =
+            FixedWindowLimiter::with_clock(u64::MAX, Duration::from_secs(1), clock.clone())
+                .unwrap();
+        assert_eq!(limiter.check_n(u64::MAX), Ok(Ok(())));
+        let not_until = limiter.check().unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_secs(1));
+        assert_eq!(limiter.state().available, 0);
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        assert_eq!(
+            FixedWindowLimiter::new(0, Duration::from_secs(1)).err(),
+            Some(TokenBucketError::InvalidCapacity)
+        );
+        assert_eq!(
+            FixedWindowLimiter::new(1, Duration::ZERO).err(),
+            Some(TokenBucketError::InvalidInterval)
+        );
+        assert_eq!(
+            FixedWindowLimiter::new(1, Duration::MAX).err(),
+            Some(TokenBucketError::CapacityOverflow)
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::builder::TokenBucketBuilder;
+use crate::clock::{Clock, SystemClock};
+use crate::error::{NotUntil, TokenBucketError};
+use crate::token_bucket::TokenBucket;
+
+#[cfg(test)]
+use std::time::Duration;
+
+const MIN_SWEEP_LEN: usize = 1024;
+
+/// One bucket per key, built from the same builder on first use.
+/// Buckets that have refilled to full are evicted since a new one would be identical,
+/// so the builder should start full (the default) for eviction to be lossless.
+pub struct KeyedTokenBuckets<K, C = SystemClock> {
+    builder: TokenBucketBuilder<C>,
+    buckets: HashMap<K, TokenBucket<C>>,
+    next_sweep_len: usize,
+}
+impl<K: Hash + Eq + Clone, C: Clock + Clone> KeyedTokenBuckets<K, C> {
+    pub fn new(builder: TokenBucketBuilder<C>) -> Result<Self, TokenBucketError> {
+        builder.build()?;
+        Ok(KeyedTokenBuckets {
+            builder,
+            buckets: HashMap::new(),
+            next_sweep_len: MIN_SWEEP_LEN,
+        })
+    }
+
+    pub fn try_take(&mut self, key: &K) -> Result<(), TokenBucketError> {
+        self.try_take_n(key, 1)
+    }
+
+    pub fn try_take_n(&mut self, key: &K, n: u64) -> Result<(), TokenBucketError> {
+        self.bucket(key)?.try_take_n(n)
+    }
+
+    pub fn check(&mut self, key: &K) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        self.check_n(key, 1)
+    }
+
+    pub fn check_n(&mut self, key: &K, n: u64) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        self.bucket(key)?.check_n(n)
+    }
+
+    /// Tokens available for key, unseen keys get a fresh bucket's count
+    pub fn available(&self, key: &K) -> Result<u64, TokenBucketError> {
+        match self.buckets.get(key) {
+            Some(bucket) => Ok(bucket.available()),
+            None => Ok(self.builder.build()?.available()),
+        }
+    }
+
+    /// Removes buckets that have refilled to full
+    pub fn evict_full(&mut self) {
+        self.buckets
+            .retain(|_, bucket| !bucket.time_until_full().is_zero());
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    // Sweeps whenever the map doubles so eviction stays amortized O(1) per key
+    fn bucket(&mut self, key: &K) -> Result<&mut TokenBucket<C>, TokenBucketError> {
+        if !self.buckets.contains_key(key) {
+            if self.buckets.len() >= self.next_sweep_len {
+                self.evict_full();
+                self.next_sweep_len = cmp::max(MIN_SWEEP_LEN, self.buckets.len() * 2);
+            }
+            self.buckets.insert(key.clone(), self.builder.build()?);
+        }
+        Ok(self.buckets.get_mut(key).expect("bucket was just inserted"))
+    }
+}
+
+#[cfg(test)]
+mod test_try_take {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn keyed(clock: &MockClock) -> KeyedTokenBuckets<&'static str, MockClock> {
+        let builder = TokenBucketBuilder::new()
+            .rate(1, Duration::from_millis(10))
+            .burst(2)
+            .clock(clock.clone());
+        KeyedTokenBuckets::new(builder).unwrap()
+    }
+
+    #[test]
+    fn keys_have_separate_buckets() {
+        let clock = MockClock::new();
+        let mut keyed = keyed(&clock);
+        assert_eq!(keyed.try_take_n(&"a", 2), Ok(()));
+        assert!(keyed.try_take(&"a").is_err());
+        assert_eq!(keyed.try_take_n(&"b", 2), Ok(()));
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(keyed.try_take(&"a"), Ok(()));
+        assert_eq!(keyed.available(&"b"), Ok(1));
+        assert_eq!(keyed.available(&"c"), Ok(2));
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        let builder = TokenBucketBuilder::new().burst(1);
+        assert_eq!(
+            KeyedTokenBuckets::<u32>::new(builder).err(),
+            Some(TokenBucketError::InvalidInterval)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_evict_full {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn keeps_partially_drained_buckets() {
+        let clock = MockClock::new();
+        let builder = TokenBucketBuilder::new()
+            .rate(1, Duration::from_millis(10))
+            .burst(2)
+            .clock(clock.clone());
+        let mut keyed = KeyedTokenBuckets::new(builder).unwrap();
+        keyed.try_take(&1).unwrap();
+        clock.advance(Duration::from_millis(5));
+        keyed.try_take(&2).unwrap();
+        clock.advance(Duration::from_millis(5));
+        keyed.evict_full();
+        assert_eq!(keyed.len(), 1);
+        clock.advance(Duration::from_millis(5));
+        keyed.evict_full();
+        assert!(keyed.is_empty());
+    }
+
+    #[test]
+    fn sweeps_automatically_as_keys_grow() {
+        let clock = MockClock::new();
+        let builder = TokenBucketBuilder::new()
+            .rate(1, Duration::from_millis(1))
+            .clock(clock.clone());
+        let mut keyed = KeyedTokenBuckets::new(builder).unwrap();
+        for key in 0..100_000 {
+            keyed.try_take(&key).unwrap();
+            clock.advance(Duration::from_micros(10));
+        }
+        assert!(keyed.len() <= 2 * MIN_SWEEP_LEN);
+    }
+}
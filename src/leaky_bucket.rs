@@ -0,0 +1,252 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::TokenBucketError;
+use crate::token_bucket::TokenBucket;
+
+#[cfg(test)]
+use std::sync::Arc;
+#[cfg(test)]
+use std::thread;
+
+/// What push does when the queue is at max depth
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropNewest,
+    DropOldest,
+    Block,
+}
+
+/// Queues items and releases them one per refresh interval.
+/// A TokenBucket with room for a single token paces the releases.
+pub struct LeakyBucket<T, C = SystemClock> {
+    state: Mutex<State<T, C>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    max_depth: usize,
+    policy: OverflowPolicy,
+    clock: C,
+}
+struct State<T, C> {
+    queue: VecDeque<T>,
+    bucket: TokenBucket<C>,
+}
+impl<T> LeakyBucket<T> {
+    pub fn new(
+        refresh_interval: Duration,
+        max_depth: usize,
+        policy: OverflowPolicy,
+    ) -> Result<LeakyBucket<T>, TokenBucketError> {
+        LeakyBucket::with_clock(refresh_interval, max_depth, policy, SystemClock)
+    }
+}
+impl<T, C: Clock + Clone> LeakyBucket<T, C> {
+    pub fn with_clock(
+        refresh_interval: Duration,
+        max_depth: usize,
+        policy: OverflowPolicy,
+        clock: C,
+    ) -> Result<LeakyBucket<T, C>, TokenBucketError> {
+        if max_depth == 0 {
+            return Err(TokenBucketError::InvalidCapacity);
+        }
+        Ok(LeakyBucket {
+            state: Mutex::new(State {
+                queue: VecDeque::with_capacity(max_depth),
+                bucket: TokenBucket::with_clock(refresh_interval, 1, 1, clock.clone())?,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            max_depth,
+            policy,
+            clock,
+        })
+    }
+
+    /// Returns the item dropped to make room, if any.
+    /// With OverflowPolicy::Block waits for a pop instead of dropping.
+    pub fn push(&self, item: T) -> Option<T> {
+        let mut state = self.lock();
+        let mut dropped = None;
+        if state.queue.len() >= self.max_depth {
+            match self.policy {
+                OverflowPolicy::DropNewest => return Some(item),
+                OverflowPolicy::DropOldest => dropped = state.queue.pop_front(),
+                OverflowPolicy::Block => {
+                    while state.queue.len() >= self.max_depth {
+                        state = self
+                            .not_full
+                            .wait(state)
+                            .unwrap_or_else(PoisonError::into_inner);
+                    }
+                }
+            }
+        }
+        state.queue.push_back(item);
+        self.not_empty.notify_one();
+        dropped
+    }
+
+    /// Releases the next item if its turn has come
+    pub fn try_pop(&self) -> Option<T> {
+        let mut state = self.lock();
+        if state.queue.is_empty() || state.bucket.check() != Ok(Ok(())) {
+            return None;
+        }
+        self.release(&mut state)
+    }
+
+    /// Waits for an item and then for its turn, try_pop doesn't wait.
+    /// Fails only when the clock can't represent the release after it.
+    pub fn pop(&self) -> Result<T, TokenBucketError> {
+        let mut state = self.lock();
+        loop {
+            if state.queue.is_empty() {
+                state = self
+                    .not_empty
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+            match state.bucket.check()? {
+                Ok(()) => return Ok(self.release(&mut state).expect("queue isn't empty")),
+                Err(not_until) => {
+                    drop(state);
+                    self.clock.sleep(not_until.wait);
+                    state = self.lock();
+                }
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().queue.is_empty()
+    }
+
+    fn release(&self, state: &mut State<T, C>) -> Option<T> {
+        let item = state.queue.pop_front();
+        self.not_full.notify_one();
+        item
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<T, C>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod test_pop {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn leaky(clock: &MockClock, policy: OverflowPolicy) -> LeakyBucket<u32, MockClock> {
+        LeakyBucket::with_clock(Duration::from_millis(10), 3, policy, clock.clone()).unwrap()
+    }
+
+    #[test]
+    fn releases_at_steady_rate() {
+        let clock = MockClock::new();
+        let leaky = leaky(&clock, OverflowPolicy::DropNewest);
+        for item in 0..3 {
+            assert_eq!(leaky.push(item), None);
+        }
+        assert_eq!(leaky.try_pop(), Some(0));
+        assert_eq!(leaky.try_pop(), None);
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(leaky.try_pop(), Some(1));
+        assert_eq!(leaky.pop(), Ok(2));
+        assert_eq!(clock.elapsed(), Duration::from_millis(20));
+        assert_eq!(leaky.try_pop(), None);
+        assert!(leaky.is_empty());
+    }
+
+    #[test]
+    fn does_not_burst_after_idle() {
+        let clock = MockClock::new();
+        let leaky = leaky(&clock, OverflowPolicy::DropNewest);
+        clock.advance(Duration::from_secs(60));
+        leaky.push(0);
+        leaky.push(1);
+        assert_eq!(leaky.try_pop(), Some(0));
+        assert_eq!(leaky.try_pop(), None);
+    }
+
+    #[test]
+    fn waits_for_an_item() {
+        let clock = MockClock::new();
+        let leaky = Arc::new(leaky(&clock, OverflowPolicy::DropNewest));
+        let consumer = {
+            let leaky = Arc::clone(&leaky);
+            thread::spawn(move || leaky.pop())
+        };
+        thread::sleep(Duration::from_millis(10));
+        assert!(!consumer.is_finished());
+        leaky.push(7);
+        assert_eq!(consumer.join().unwrap(), Ok(7));
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+    }
+
+    #[test]
+    fn rejects_zero_depth() {
+        assert_eq!(
+            LeakyBucket::<u32>::new(Duration::from_millis(1), 0, OverflowPolicy::Block).err(),
+            Some(TokenBucketError::InvalidCapacity)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_push {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn leaky(clock: &MockClock, policy: OverflowPolicy) -> LeakyBucket<u32, MockClock> {
+        LeakyBucket::with_clock(Duration::from_millis(10), 2, policy, clock.clone()).unwrap()
+    }
+
+    #[test]
+    fn drop_newest() {
+        let clock = MockClock::new();
+        let leaky = leaky(&clock, OverflowPolicy::DropNewest);
+        assert_eq!(leaky.push(0), None);
+        assert_eq!(leaky.push(1), None);
+        assert_eq!(leaky.push(2), Some(2));
+        assert_eq!(leaky.len(), 2);
+        assert_eq!(leaky.pop(), Ok(0));
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let clock = MockClock::new();
+        let leaky = leaky(&clock, OverflowPolicy::DropOldest);
+        leaky.push(0);
+        leaky.push(1);
+        assert_eq!(leaky.push(2), Some(0));
+        assert_eq!(leaky.len(), 2);
+        assert_eq!(leaky.pop(), Ok(1));
+        assert_eq!(leaky.pop(), Ok(2));
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let leaky = Arc::new(
+            LeakyBucket::new(Duration::from_millis(20), 1, OverflowPolicy::Block).unwrap(),
+        );
+        leaky.push(0);
+        let producer = {
+            let leaky = Arc::clone(&leaky);
+            thread::spawn(move || leaky.push(1))
+        };
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(leaky.len(), 1);
+        assert_eq!(leaky.pop(), Ok(0));
+        assert_eq!(producer.join().unwrap(), None);
+        assert_eq!(leaky.pop(), Ok(1));
+    }
+}
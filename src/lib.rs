@@ -0,0 +1,21 @@
+pub mod atomic_token_bucket;
+pub mod builder;
+pub mod clock;
+pub mod composite;
+pub mod delay;
+pub mod error;
+pub mod fair_share;
+pub mod fixed_window;
+pub mod keyed;
+pub mod leaky_bucket;
+pub mod limiter;
+pub mod multi_window;
+pub mod priority;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod reservation;
+pub mod sharded;
+pub mod sliding_window;
+pub mod snapshot;
+pub mod store;
+pub mod token_bucket;
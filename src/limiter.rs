@@ -0,0 +1,162 @@
+use crate::clock::Clock;
+use crate::error::{NotUntil, TokenBucketError};
+use crate::token_bucket::TokenBucket;
+
+/// Point in time view of a limiter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LimiterState {
+    pub available: u64,
+    pub capacity: u64,
+}
+
+/// Shared by every limiter algorithm so integrations can be written once,
+/// works as Box<dyn RateLimiter> too
+pub trait RateLimiter {
+    /// Outer error when n can never fit, inner when the tokens aren't available yet
+    fn check_n(&mut self, n: u64) -> Result<Result<(), NotUntil>, TokenBucketError>;
+
+    /// Sleeps on the limiter's clock until n tokens are taken
+    fn wait_n(&mut self, n: u64) -> Result<(), TokenBucketError>;
+
+    fn state(&self) -> LimiterState;
+
+    fn check(&mut self) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        self.check_n(1)
+    }
+
+    fn wait(&mut self) -> Result<(), TokenBucketError> {
+        self.wait_n(1)
+    }
+}
+
+impl<L: RateLimiter + ?Sized> RateLimiter for Box<L> {
+    fn check_n(&mut self, n: u64) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        (**self).check_n(n)
+    }
+
+    fn wait_n(&mut self, n: u64) -> Result<(), TokenBucketError> {
+        (**self).wait_n(n)
+    }
+
+    fn state(&self) -> LimiterState {
+        (**self).state()
+    }
+}
+
+impl<L: RateLimiter + ?Sized> RateLimiter for &mut L {
+    fn check_n(&mut self, n: u64) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        (**self).check_n(n)
+    }
+
+    fn wait_n(&mut self, n: u64) -> Result<(), TokenBucketError> {
+        (**self).wait_n(n)
+    }
+
+    fn state(&self) -> LimiterState {
+        (**self).state()
+    }
+}
+
+impl<C: Clock> RateLimiter for TokenBucket<C> {
+    fn check_n(&mut self, n: u64) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        TokenBucket::check_n(self, n)
+    }
+
+    fn wait_n(&mut self, n: u64) -> Result<(), TokenBucketError> {
+        self.take_n(n)
+    }
+
+    fn state(&self) -> LimiterState {
+        LimiterState {
+            available: self.available(),
+            capacity: self.capacity(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_rate_limiter {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::fixed_window::FixedWindowLimiter;
+    use crate::sliding_window::{SlidingWindowCounterLimiter, SlidingWindowLogLimiter};
+    use std::time::Duration;
+
+    fn admitted<L: RateLimiter>(limiter: &mut L, attempts: u32) -> u32 {
+        (0..attempts)
+            .filter(|_| limiter.check() == Ok(Ok(())))
+            .count() as u32
+    }
+
+    #[test]
+    fn algorithms_are_interchangeable() {
+        let clock = MockClock::new();
+        let second = Duration::from_secs(1);
+        let mut token_bucket =
+            TokenBucket::with_clock(Duration::from_millis(200), 5, 5, clock.clone()).unwrap();
+        let mut fixed = FixedWindowLimiter::with_clock(5, second, clock.clone()).unwrap();
+        let mut log = SlidingWindowLogLimiter::with_clock(5, second, clock.clone()).unwrap();
+        let mut counter = SlidingWindowCounterLimiter::with_clock(5, second, clock).unwrap();
+        assert_eq!(admitted(&mut token_bucket, 10), 5);
+        assert_eq!(admitted(&mut fixed, 10), 5);
+        assert_eq!(admitted(&mut log, 10), 5);
+        assert_eq!(admitted(&mut counter, 10), 5);
+    }
+
+    #[test]
+    fn works_through_dyn() {
+        let clock = MockClock::new();
+        let second = Duration::from_secs(1);
+        let mut limiters: Vec<Box<dyn RateLimiter>> = vec![
+            Box::new(
+                TokenBucket::with_clock(Duration::from_millis(500), 2, 2, clock.clone()).unwrap(),
+            ),
+            Box::new(FixedWindowLimiter::with_clock(2, second, clock.clone()).unwrap()),
+            Box::new(SlidingWindowLogLimiter::with_clock(2, second, clock.clone()).unwrap()),
+            Box::new(SlidingWindowCounterLimiter::with_clock(2, second, clock.clone()).unwrap()),
+        ];
+        for limiter in &mut limiters {
+            assert_eq!(
+                limiter.state(),
+                LimiterState {
+                    available: 2,
+                    capacity: 2
+                }
+            );
+            assert_eq!(limiter.check_n(2), Ok(Ok(())));
+            assert_eq!(limiter.state().available, 0);
+            assert!(limiter.check().unwrap().is_err());
+            assert_eq!(limiter.check_n(3), Err(TokenBucketError::CapacityOverflow));
+        }
+    }
+
+    #[test]
+    fn wait_sleeps_on_the_limiter_clock() {
+        let clock = MockClock::new();
+        let second = Duration::from_secs(1);
+        let mut limiters: Vec<Box<dyn RateLimiter>> = vec![
+            Box::new(
+                TokenBucket::with_clock(Duration::from_millis(500), 2, 2, clock.clone()).unwrap(),
+            ),
+            Box::new(FixedWindowLimiter::with_clock(2, second, clock.clone()).unwrap()),
+            Box::new(SlidingWindowLogLimiter::with_clock(2, second, clock.clone()).unwrap()),
+            Box::new(SlidingWindowCounterLimiter::with_clock(2, second, clock.clone()).unwrap()),
+        ];
+        for limiter in &mut limiters {
+            let start = clock.elapsed();
+            assert_eq!(limiter.wait_n(2), Ok(()));
+            assert_eq!(limiter.wait(), Ok(()));
+            assert!(clock.elapsed() > start);
+            assert_eq!(limiter.wait_n(3), Err(TokenBucketError::CapacityOverflow));
+        }
+    }
+
+    #[test]
+    fn generic_code_accepts_mutable_references() {
+        let clock = MockClock::new();
+        let mut token_bucket =
+            TokenBucket::with_clock(Duration::from_millis(200), 5, 5, clock).unwrap();
+        assert_eq!(admitted(&mut &mut token_bucket, 3), 3);
+        assert_eq!(token_bucket.state().available, 2);
+    }
+}
@@ -1,3 +1 @@
-mod token_bucket;
-
 fn main() { }
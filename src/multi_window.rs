@@ -0,0 +1,189 @@
+use std::time::Duration;
+
+use crate::builder::TokenBucketBuilder;
+use crate::clock::{Clock, SystemClock};
+use crate::composite::CompositeLimiter;
+use crate::error::{NotUntil, TokenBucketError};
+use crate::limiter::{LimiterState, RateLimiter};
+use crate::token_bucket::TokenBucket;
+
+/// Limits like 10 per second and 500 per minute at once, every band must have the tokens
+pub struct MultiWindowLimiter<C = SystemClock> {
+    bands: CompositeLimiter<C>,
+}
+impl MultiWindowLimiter {
+    pub fn builder() -> MultiWindowBuilder {
+        MultiWindowBuilder {
+            bands: Vec::new(),
+            clock: SystemClock,
+        }
+    }
+}
+impl<C: Clock> MultiWindowLimiter<C> {
+    /// In the order they were added
+    pub fn bands(&self) -> impl Iterator<Item = &TokenBucket<C>> {
+        self.bands.levels().map(|(_, bucket)| bucket)
+    }
+
+    pub fn check(&mut self) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        self.check_n(1)
+    }
+
+    /// Takes from every band or none, denied with the longest wait of any band
+    pub fn check_n(&mut self, n: u64) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        RateLimiter::check_n(&mut self.bands, n)
+    }
+}
+impl<C: Clock> RateLimiter for MultiWindowLimiter<C> {
+    fn check_n(&mut self, n: u64) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        MultiWindowLimiter::check_n(self, n)
+    }
+
+    fn wait_n(&mut self, n: u64) -> Result<(), TokenBucketError> {
+        self.bands.wait_n(n)
+    }
+
+    fn state(&self) -> LimiterState {
+        self.bands.state()
+    }
+}
+
+/// Each band starts full with a burst of its limit
+#[derive(Clone, Debug)]
+pub struct MultiWindowBuilder<C = SystemClock> {
+    bands: Vec<(u64, Duration)>,
+    clock: C,
+}
+impl<C: Clock + Clone> MultiWindowBuilder<C> {
+    pub fn band(mut self, limit: u64, per: Duration) -> Self {
+        self.bands.push((limit, per));
+        self
+    }
+
+    pub fn clock<D: Clock + Clone>(self, clock: D) -> MultiWindowBuilder<D> {
+        MultiWindowBuilder {
+            bands: self.bands,
+            clock,
+        }
+    }
+
+    pub fn build(&self) -> Result<MultiWindowLimiter<C>, TokenBucketError> {
+        let mut bands: Option<CompositeLimiter<C>> = None;
+        for &(limit, per) in &self.bands {
+            let bucket = TokenBucketBuilder::new()
+                .rate(limit, per)
+                .clock(self.clock.clone())
+                .build()?;
+            let name = format!("{} per {:?}", limit, per);
+            bands = Some(match bands {
+                Some(bands) => bands.with_level(name, bucket),
+                None => CompositeLimiter::new(name, bucket),
+            });
+        }
+        bands
+            .map(|bands| MultiWindowLimiter { bands })
+            .ok_or(TokenBucketError::InvalidInterval)
+    }
+}
+
+#[cfg(test)]
+mod test_check_n {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn vendor_limits(clock: &MockClock) -> MultiWindowLimiter<MockClock> {
+        MultiWindowLimiter::builder()
+            .band(10, Duration::from_secs(1))
+            .band(50, Duration::from_secs(60))
+            .clock(clock.clone())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn short_band_limits_bursts() {
+        let clock = MockClock::new();
+        let mut limiter = vendor_limits(&clock);
+        assert_eq!(limiter.check_n(10), Ok(Ok(())));
+        let not_until = limiter.check().unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn long_band_limits_sustained_rate() {
+        let clock = MockClock::new();
+        let mut limiter = vendor_limits(&clock);
+        for _ in 0..5 {
+            assert_eq!(limiter.check_n(10), Ok(Ok(())));
+            clock.advance(Duration::from_secs(1));
+        }
+        // the per second band is full again but the per minute one is not
+        let not_until = limiter.check_n(10).unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_secs(7));
+        let available: Vec<u64> = limiter.bands().map(|band| band.available()).collect();
+        assert_eq!(available, [10, 4]);
+    }
+
+    #[test]
+    fn denied_request_takes_from_no_band() {
+        let clock = MockClock::new();
+        let mut limiter = vendor_limits(&clock);
+        limiter.check_n(10).unwrap().unwrap();
+        assert!(limiter.check().unwrap().is_err());
+        let available: Vec<u64> = limiter.bands().map(|band| band.available()).collect();
+        assert_eq!(available, [0, 40]);
+    }
+
+    #[test]
+    fn rejects_more_than_the_smallest_band() {
+        let clock = MockClock::new();
+        let mut limiter = vendor_limits(&clock);
+        assert_eq!(limiter.check_n(11), Err(TokenBucketError::CapacityOverflow));
+    }
+
+    #[test]
+    fn requires_a_valid_band() {
+        let builder = MultiWindowLimiter::builder();
+        assert_eq!(
+            builder.build().err(),
+            Some(TokenBucketError::InvalidInterval)
+        );
+        let builder = builder
+            .band(10, Duration::from_secs(1))
+            .band(0, Duration::from_secs(1));
+        assert_eq!(
+            builder.build().err(),
+            Some(TokenBucketError::InvalidInterval)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_rate_limiter {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn waits_for_every_band() {
+        let clock = MockClock::new();
+        let mut limiter: Box<dyn RateLimiter> = Box::new(
+            MultiWindowLimiter::builder()
+                .band(2, Duration::from_secs(1))
+                .band(3, Duration::from_secs(60))
+                .clock(clock.clone())
+                .build()
+                .unwrap(),
+        );
+        assert_eq!(
+            limiter.state(),
+            LimiterState {
+                available: 2,
+                capacity: 2
+            }
+        );
+        limiter.wait_n(2).unwrap();
+        limiter.wait_n(2).unwrap();
+        assert_eq!(clock.elapsed(), Duration::from_secs(20));
+        assert_eq!(limiter.state().available, 0);
+    }
+}
@@ -0,0 +1,320 @@
+use std::cmp::{self, Reverse};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::TokenBucketError;
+use crate::token_bucket::TokenBucket;
+
+#[cfg(test)]
+use crate::clock::MockClock;
+#[cfg(test)]
+use std::sync::Arc;
+#[cfg(test)]
+use std::thread;
+#[cfg(test)]
+use std::time::Duration;
+
+/// Blocking take where waiters are served highest priority first, oldest first within a priority.
+/// A waiter passed over max_bypass times by younger waiters is served before any priority,
+/// so a max_bypass of 0 serves everyone in arrival order.
+pub struct PriorityTokenBucket<C = SystemClock> {
+    state: Mutex<State<C>>,
+    changed: Condvar,
+    max_bypass: u32,
+    clock: C,
+}
+struct State<C> {
+    bucket: TokenBucket<C>,
+    waiters: Vec<Waiter>,
+    next_seq: u64,
+}
+struct Waiter {
+    seq: u64,
+    priority: u8,
+    bypassed: u32,
+}
+impl<C: Clock + Clone> PriorityTokenBucket<C> {
+    pub fn new(bucket: TokenBucket<C>, max_bypass: u32) -> PriorityTokenBucket<C> {
+        PriorityTokenBucket {
+            clock: bucket.clock().clone(),
+            state: Mutex::new(State {
+                bucket,
+                waiters: Vec::new(),
+                next_seq: 0,
+            }),
+            changed: Condvar::new(),
+            max_bypass,
+        }
+    }
+
+    pub fn take(&self, priority: u8) -> Result<(), TokenBucketError> {
+        self.take_n(1, priority)
+    }
+
+    /// Waits for its turn in the queue and then for the tokens.
+    /// Fails instead of waiting when n is more than max capacity.
+    pub fn take_n(&self, n: u64, priority: u8) -> Result<(), TokenBucketError> {
+        let seq = {
+            let mut state = self.lock();
+            state.bucket.time_until_available(n)?;
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.waiters.push(Waiter {
+                seq,
+                priority,
+                bypassed: 0,
+            });
+            seq
+        };
+        // declared before state so the lock is released when it leaves the queue
+        let _queued = Queued { queue: self, seq };
+        let mut state = self.lock();
+        loop {
+            if self.head(&state.waiters) != Some(seq) {
+                state = self
+                    .changed
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+            match state.bucket.check_n(n)? {
+                Ok(()) => {
+                    self.served(&mut state, seq);
+                    return Ok(());
+                }
+                // refill isn't signalled so the head sleeps until its tokens are due,
+                // anyone who overtakes it in the meantime is served first when it wakes
+                Err(not_until) => {
+                    drop(state);
+                    self.clock.sleep(not_until.wait);
+                    state = self.lock();
+                }
+            }
+        }
+    }
+
+    /// Only succeeds when nobody is waiting, so it never jumps the queue.
+    /// Tokens there while others wait are theirs, so retry no sooner than the next refill.
+    pub fn try_take_n(&self, n: u64) -> Result<(), TokenBucketError> {
+        let mut state = self.lock();
+        if !state.waiters.is_empty() {
+            return Err(TokenBucketError::InsufficientTokens {
+                retry_after: cmp::max(
+                    state.bucket.time_until_available(n)?,
+                    state.bucket.refill_interval(),
+                ),
+            });
+        }
+        state.bucket.try_take_n(n)
+    }
+
+    pub fn available(&self) -> u64 {
+        self.lock().bucket.available()
+    }
+
+    /// Callers currently blocked in take
+    pub fn waiting(&self) -> usize {
+        self.lock().waiters.len()
+    }
+
+    fn head(&self, waiters: &[Waiter]) -> Option<u64> {
+        waiters
+            .iter()
+            .max_by_key(|waiter| {
+                let starving = waiter.bypassed >= self.max_bypass;
+                let priority = if starving { 0 } else { waiter.priority };
+                (starving, priority, Reverse(waiter.seq))
+            })
+            .map(|waiter| waiter.seq)
+    }
+
+    // Everyone who arrived earlier was bypassed, Queued then wakes the rest
+    fn served(&self, state: &mut State<C>, seq: u64) {
+        state.waiters.retain(|waiter| waiter.seq != seq);
+        for waiter in state.waiters.iter_mut().filter(|waiter| waiter.seq < seq) {
+            waiter.bypassed += 1;
+        }
+    }
+}
+impl<C> PriorityTokenBucket<C> {
+    fn lock(&self) -> MutexGuard<'_, State<C>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Leaves the queue however take_n returns, so an error or panic can't block everyone behind it
+struct Queued<'a, C> {
+    queue: &'a PriorityTokenBucket<C>,
+    seq: u64,
+}
+impl<C> Drop for Queued<'_, C> {
+    fn drop(&mut self) {
+        self.queue
+            .lock()
+            .waiters
+            .retain(|waiter| waiter.seq != self.seq);
+        self.queue.changed.notify_all();
+    }
+}
+
+// Sleeping only yields, time moves when the test advances it,
+// so with room for one token each step serves exactly one waiter
+#[cfg(test)]
+#[derive(Clone)]
+struct SteppedClock(MockClock);
+#[cfg(test)]
+impl Clock for SteppedClock {
+    fn now(&self) -> std::time::Instant {
+        self.0.now()
+    }
+
+    fn sleep(&self, _: Duration) {
+        thread::yield_now();
+    }
+}
+
+// Fails instead of hanging when the other threads never get there
+#[cfg(test)]
+fn spin_until(done: impl Fn() -> bool) {
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    while !done() {
+        assert!(std::time::Instant::now() < deadline, "timed out");
+        thread::yield_now();
+    }
+}
+
+#[cfg(test)]
+mod test_take_n {
+    use super::*;
+    use std::panic;
+
+    // Everyone queues up in the given order before the first refill
+    fn served_order(max_bypass: u32, priorities: &[u8]) -> Vec<u8> {
+        let clock = SteppedClock(MockClock::new());
+        let bucket = TokenBucket::with_clock(Duration::from_millis(50), 1, 0, clock.clone());
+        let queue = Arc::new(PriorityTokenBucket::new(bucket.unwrap(), max_bypass));
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let waiters: Vec<_> = priorities
+            .iter()
+            .enumerate()
+            .map(|(queued, &priority)| {
+                let waiter = {
+                    let (queue, served) = (queue.clone(), served.clone());
+                    thread::spawn(move || {
+                        queue.take(priority).unwrap();
+                        served.lock().unwrap().push(priority);
+                    })
+                };
+                spin_until(|| queue.waiting() > queued);
+                waiter
+            })
+            .collect();
+        for served_so_far in 1..=priorities.len() {
+            clock.0.advance(Duration::from_millis(50));
+            spin_until(|| served.lock().unwrap().len() == served_so_far);
+        }
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        Arc::try_unwrap(served).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn serves_highest_priority_first() {
+        assert_eq!(served_order(u32::MAX, &[1, 2, 9, 5]), [9, 5, 2, 1]);
+    }
+
+    #[test]
+    fn bounds_how_often_a_waiter_is_bypassed() {
+        assert_eq!(served_order(u32::MAX, &[1, 9, 8, 7]), [9, 8, 7, 1]);
+        assert_eq!(served_order(1, &[1, 9, 8, 7]), [9, 1, 8, 7]);
+    }
+
+    #[test]
+    fn zero_max_bypass_is_fifo() {
+        assert_eq!(served_order(0, &[1, 2, 9, 5]), [1, 2, 9, 5]);
+    }
+
+    #[test]
+    fn waits_for_tokens_on_the_bucket_clock() {
+        let clock = MockClock::new();
+        let bucket = TokenBucket::with_clock(Duration::from_millis(10), 2, 0, clock.clone());
+        let queue = PriorityTokenBucket::new(bucket.unwrap(), 0);
+        assert_eq!(queue.take_n(2, 0), Ok(()));
+        assert_eq!(clock.elapsed(), Duration::from_millis(20));
+        assert_eq!(queue.waiting(), 0);
+    }
+
+    // Sleeping for tokens unwinds, as a failing clock would
+    #[derive(Clone)]
+    struct PanickingClock(MockClock);
+    impl Clock for PanickingClock {
+        fn now(&self) -> std::time::Instant {
+            self.0.now()
+        }
+
+        fn sleep(&self, _: Duration) {
+            panic!("clock failed");
+        }
+    }
+
+    #[test]
+    fn leaves_the_queue_when_take_unwinds() {
+        let clock = PanickingClock(MockClock::new());
+        let bucket = TokenBucket::with_clock(Duration::from_millis(10), 1, 0, clock.clone());
+        let queue = PriorityTokenBucket::new(bucket.unwrap(), 0);
+        let taken = panic::catch_unwind(panic::AssertUnwindSafe(|| queue.take(0)));
+        assert!(taken.is_err());
+        assert_eq!(queue.waiting(), 0);
+        clock.0.advance(Duration::from_millis(10));
+        assert_eq!(queue.try_take_n(1), Ok(()));
+    }
+
+    #[test]
+    fn fails_instead_of_waiting_forever() {
+        let bucket = TokenBucket::new(Duration::from_millis(10), 2, 2).unwrap();
+        let queue = PriorityTokenBucket::new(bucket, 0);
+        assert_eq!(queue.take_n(3, 0), Err(TokenBucketError::CapacityOverflow));
+        assert_eq!(queue.waiting(), 0);
+    }
+}
+
+#[cfg(test)]
+mod test_try_take_n {
+    use super::*;
+
+    #[test]
+    fn takes_when_nobody_waits() {
+        let clock = MockClock::new();
+        let bucket = TokenBucket::with_clock(Duration::from_millis(10), 2, 2, clock).unwrap();
+        let queue = PriorityTokenBucket::new(bucket, 0);
+        assert_eq!(queue.try_take_n(2), Ok(()));
+        assert_eq!(
+            queue.try_take_n(1),
+            Err(TokenBucketError::InsufficientTokens {
+                retry_after: Duration::from_millis(10)
+            })
+        );
+        assert_eq!(queue.available(), 0);
+    }
+
+    #[test]
+    fn does_not_jump_the_queue() {
+        let clock = SteppedClock(MockClock::new());
+        let bucket = TokenBucket::with_clock(Duration::from_millis(50), 1, 0, clock.clone());
+        let queue = Arc::new(PriorityTokenBucket::new(bucket.unwrap(), 0));
+        let waiter = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.take(0))
+        };
+        spin_until(|| queue.waiting() == 1);
+        assert_eq!(
+            queue.try_take_n(1),
+            Err(TokenBucketError::InsufficientTokens {
+                retry_after: Duration::from_millis(50)
+            })
+        );
+        clock.0.advance(Duration::from_millis(50));
+        assert_eq!(waiter.join().unwrap(), Ok(()));
+    }
+}
@@ -0,0 +1,719 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::{NotUntil, TokenBucketError};
+use crate::store::{RateLimitStore, StoreError};
+
+/// TokenBucket's algorithm run atomically by the server on its own clock.
+/// Lua only has doubles, so timestamps are microseconds since the unix epoch.
+/// replicate_commands lets servers before 5.0 write after the non-deterministic TIME.
+/// Keys expire once their bucket would be full again, absent keys are full buckets.
+const TAKE_SCRIPT: &str = r#"
+redis.replicate_commands()
+local max_refresh = tonumber(ARGV[1])
+local refresh = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+local effective_last_refreshed = now - max_refresh
+local stored = redis.call('GET', KEYS[1])
+if stored then
+    local last_refreshed = tonumber(stored)
+    if last_refreshed > effective_last_refreshed then
+        effective_last_refreshed = last_refreshed
+    end
+end
+local new_last_refreshed = effective_last_refreshed + refresh
+if new_last_refreshed > now then
+    return new_last_refreshed - now
+end
+local expire_ms = math.ceil((new_last_refreshed + max_refresh - now) / 1000) + 1
+redis.call('SET', KEYS[1], string.format('%d', new_last_refreshed), 'PX', expire_ms)
+return 0
+"#;
+
+/// An empty ARGV[1] means the key must be absent
+const COMPARE_AND_SET_SCRIPT: &str = r#"
+local current = redis.call('GET', KEYS[1])
+if (current == false and ARGV[1] == '') or current == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[2])
+    return 1
+end
+return 0
+"#;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RespValue {
+    Simple(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<RespValue>>),
+}
+
+/// Minimal RESP client, one command in flight at a time
+pub struct RedisConnection {
+    stream: Mutex<BufReader<TcpStream>>,
+    script_shas: Mutex<HashMap<&'static str, String>>,
+}
+impl RedisConnection {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<RedisConnection> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(RedisConnection {
+            stream: Mutex::new(BufReader::new(stream)),
+            script_shas: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Error replies come back as io errors
+    pub fn command(&self, args: &[&[u8]]) -> io::Result<RespValue> {
+        let mut stream = lock(&self.stream);
+        write_command(stream.get_mut(), args)?;
+        read_value(&mut *stream)
+    }
+
+    // EVALSHA, loading the script first and again if the server lost it
+    fn eval(&self, script: &'static str, key: &str, args: &[&[u8]]) -> io::Result<RespValue> {
+        let cached = lock(&self.script_shas).get(script).cloned();
+        let sha = match cached {
+            Some(sha) => sha,
+            None => self.load_script(script)?,
+        };
+        match self.evalsha(&sha, key, args) {
+            Err(err) if err.to_string().starts_with("NOSCRIPT") => {
+                let sha = self.load_script(script)?;
+                self.evalsha(&sha, key, args)
+            }
+            result => result,
+        }
+    }
+
+    fn evalsha(&self, sha: &str, key: &str, args: &[&[u8]]) -> io::Result<RespValue> {
+        let mut command: Vec<&[u8]> = vec![b"EVALSHA", sha.as_bytes(), b"1", key.as_bytes()];
+        command.extend_from_slice(args);
+        self.command(&command)
+    }
+
+    fn load_script(&self, script: &'static str) -> io::Result<String> {
+        let sha = match self.command(&[b"SCRIPT", b"LOAD", script.as_bytes()])? {
+            RespValue::Bulk(Some(sha)) => String::from_utf8(sha).map_err(invalid_data)?,
+            value => return Err(unexpected(value)),
+        };
+        lock(&self.script_shas).insert(script, sha.clone());
+        Ok(sha)
+    }
+}
+
+/// Timestamps are stored as decimal strings, compare_and_set runs as one script.
+/// Don't share keys with RedisTokenBucket, which stores microseconds.
+impl RateLimitStore for RedisConnection {
+    type Error = io::Error;
+
+    fn get(&self, key: &str) -> io::Result<Option<u64>> {
+        match self.command(&[b"GET", key.as_bytes()])? {
+            RespValue::Bulk(None) => Ok(None),
+            RespValue::Bulk(Some(value)) => parse_u64(&value).map(Some),
+            value => Err(unexpected(value)),
+        }
+    }
+
+    fn compare_and_set(&self, key: &str, current: Option<u64>, new: u64) -> io::Result<bool> {
+        let current = current.map_or_else(String::new, |current| current.to_string());
+        let new = new.to_string();
+        match self.eval(
+            COMPARE_AND_SET_SCRIPT,
+            key,
+            &[current.as_bytes(), new.as_bytes()],
+        )? {
+            RespValue::Integer(swapped) => Ok(swapped == 1),
+            value => Err(unexpected(value)),
+        }
+    }
+}
+
+/// A TokenBucket per key enforced by the redis server, so every instance shares the limit.
+/// Intervals are rounded down to whole microseconds.
+pub struct RedisTokenBucket<C = SystemClock> {
+    connection: RedisConnection,
+    refresh_interval_micros: u64,
+    max_refresh_micros: u64,
+    clock: C,
+}
+impl RedisTokenBucket {
+    pub fn new(
+        connection: RedisConnection,
+        refresh_interval: Duration,
+        max_capacity: u64,
+    ) -> Result<RedisTokenBucket, TokenBucketError> {
+        RedisTokenBucket::with_clock(connection, refresh_interval, max_capacity, SystemClock)
+    }
+}
+impl<C: Clock> RedisTokenBucket<C> {
+    /// The clock only turns waits into instants, the server's clock decides refills
+    pub fn with_clock(
+        connection: RedisConnection,
+        refresh_interval: Duration,
+        max_capacity: u64,
+        clock: C,
+    ) -> Result<RedisTokenBucket<C>, TokenBucketError> {
+        let refresh_interval_micros = u64::try_from(refresh_interval.as_micros())
+            .map_err(|_| TokenBucketError::CapacityOverflow)?;
+        if refresh_interval_micros == 0 {
+            return Err(TokenBucketError::InvalidInterval);
+        }
+        if max_capacity == 0 {
+            return Err(TokenBucketError::InvalidCapacity);
+        }
+        let max_refresh_micros = refresh_interval_micros
+            .checked_mul(max_capacity)
+            .ok_or(TokenBucketError::CapacityOverflow)?;
+        Ok(RedisTokenBucket {
+            connection,
+            refresh_interval_micros,
+            max_refresh_micros,
+            clock,
+        })
+    }
+
+    pub fn try_take(&self, key: &str) -> Result<(), StoreError<io::Error>> {
+        self.try_take_n(key, 1)
+    }
+
+    pub fn try_take_n(&self, key: &str, n: u64) -> Result<(), StoreError<io::Error>> {
+        self.check_n(key, n)?
+            .map_err(|not_until| StoreError::Bucket(not_until.into()))
+    }
+
+    /// Outer error when n can never fit or redis fails, inner when the tokens aren't available yet
+    pub fn check_n(
+        &self,
+        key: &str,
+        n: u64,
+    ) -> Result<Result<(), NotUntil>, StoreError<io::Error>> {
+        let refresh_micros = self
+            .refresh_interval_micros
+            .checked_mul(n)
+            .filter(|refresh_micros| *refresh_micros <= self.max_refresh_micros)
+            .ok_or(StoreError::Bucket(TokenBucketError::CapacityOverflow))?;
+        let max_refresh = self.max_refresh_micros.to_string();
+        let refresh = refresh_micros.to_string();
+        let reply = self
+            .connection
+            .eval(
+                TAKE_SCRIPT,
+                key,
+                &[max_refresh.as_bytes(), refresh.as_bytes()],
+            )
+            .map_err(StoreError::Store)?;
+        match reply {
+            RespValue::Integer(0) => Ok(Ok(())),
+            RespValue::Integer(wait) if wait > 0 => {
+                let wait = Duration::from_micros(wait as u64);
+                Ok(Err(NotUntil {
+                    earliest: self.clock.now() + wait,
+                    wait,
+                }))
+            }
+            value => Err(StoreError::Store(unexpected(value))),
+        }
+    }
+}
+
+fn write_command(stream: &mut impl Write, args: &[&[u8]]) -> io::Result<()> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    stream.write_all(&buf)?;
+    stream.flush()
+}
+
+fn read_value(reader: &mut impl BufRead) -> io::Result<RespValue> {
+    let line = read_line(reader)?;
+    let (kind, rest) = line
+        .split_first()
+        .ok_or_else(|| invalid_data("empty reply"))?;
+    match kind {
+        b'+' => Ok(RespValue::Simple(
+            String::from_utf8(rest.to_vec()).map_err(invalid_data)?,
+        )),
+        b'-' => Err(io::Error::other(String::from_utf8_lossy(rest).into_owned())),
+        b':' => Ok(RespValue::Integer(parse_i64(rest)?)),
+        b'$' => match parse_i64(rest)? {
+            -1 => Ok(RespValue::Bulk(None)),
+            len => {
+                let len = usize::try_from(len).map_err(invalid_data)?;
+                let mut value = vec![0; len + 2];
+                reader.read_exact(&mut value)?;
+                value.truncate(len);
+                Ok(RespValue::Bulk(Some(value)))
+            }
+        },
+        b'*' => match parse_i64(rest)? {
+            -1 => Ok(RespValue::Array(None)),
+            len => (0..len)
+                .map(|_| read_value(reader))
+                .collect::<io::Result<_>>()
+                .map(|values| RespValue::Array(Some(values))),
+        },
+        _ => Err(invalid_data("unknown reply type")),
+    }
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\r\n") {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    line.truncate(line.len() - 2);
+    Ok(line)
+}
+
+fn parse_i64(bytes: &[u8]) -> io::Result<i64> {
+    std::str::from_utf8(bytes)
+        .map_err(invalid_data)?
+        .parse()
+        .map_err(invalid_data)
+}
+
+fn parse_u64(bytes: &[u8]) -> io::Result<u64> {
+    std::str::from_utf8(bytes)
+        .map_err(invalid_data)?
+        .parse()
+        .map_err(invalid_data)
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn unexpected(value: RespValue) -> io::Error {
+    invalid_data(format!("unexpected reply {:?}", value))
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// In-process stand-in for redis-server that knows the commands used above.
+/// Scripts run in an embedded Lua 5.1 like the server's, with TIME taken from a MockClock,
+/// and like servers before 5.0 writes after TIME fail unless replicate_commands was called.
+#[cfg(test)]
+mod stand_in {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::store::unix_nanos;
+    use mlua::{Lua, Value, Variadic};
+    use std::cell::Cell;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::SystemTime;
+
+    #[derive(Default)]
+    struct State {
+        values: HashMap<Vec<u8>, (Vec<u8>, Option<SystemTime>)>,
+        scripts: HashMap<String, String>,
+    }
+
+    pub struct StandIn {
+        pub addr: SocketAddr,
+        state: Arc<Mutex<State>>,
+    }
+    impl StandIn {
+        pub fn start(clock: MockClock) -> StandIn {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let state = Arc::new(Mutex::new(State::default()));
+            let server_state = Arc::clone(&state);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = stream.unwrap();
+                    let state = Arc::clone(&server_state);
+                    let clock = clock.clone();
+                    thread::spawn(move || serve(stream, &state, &clock));
+                }
+            });
+            StandIn { addr, state }
+        }
+
+        pub fn flush_scripts(&self) {
+            lock(&self.state).scripts.clear();
+        }
+
+        pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+            lock(&self.state)
+                .values
+                .get(key.as_bytes())
+                .map(|(value, _)| value.clone())
+        }
+
+        /// Milliseconds until key expires, like PTTL
+        pub fn pttl(&self, key: &str, clock: &MockClock) -> Option<Duration> {
+            let expires = lock(&self.state).values.get(key.as_bytes())?.1?;
+            expires.duration_since(clock.system_time()).ok()
+        }
+    }
+
+    fn serve(stream: TcpStream, state: &Mutex<State>, clock: &MockClock) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        while let Ok(RespValue::Array(Some(args))) = read_value(&mut reader) {
+            let args: Vec<Vec<u8>> = args
+                .into_iter()
+                .map(|arg| match arg {
+                    RespValue::Bulk(Some(arg)) => arg,
+                    _ => panic!("commands are arrays of bulk strings"),
+                })
+                .collect();
+            let reply = execute(&args, &mut lock(state), clock);
+            writer.write_all(&reply).unwrap();
+        }
+    }
+
+    fn execute(args: &[Vec<u8>], state: &mut State, clock: &MockClock) -> Vec<u8> {
+        let now = clock.system_time();
+        state
+            .values
+            .retain(|_, (_, expires)| expires.is_none_or(|expires| expires > now));
+        match args[0].to_ascii_uppercase().as_slice() {
+            b"PING" => b"+PONG\r\n".to_vec(),
+            b"GET" => match state.values.get(&args[1]) {
+                Some((value, _)) => bulk(value),
+                None => b"$-1\r\n".to_vec(),
+            },
+            b"SCRIPT" => {
+                let script = String::from_utf8(args[2].clone()).unwrap();
+                let sha = format!("{:040x}", state.scripts.len() + 1);
+                state.scripts.insert(sha.clone(), script);
+                bulk(sha.as_bytes())
+            }
+            b"EVALSHA" => {
+                let sha = String::from_utf8(args[1].clone()).unwrap();
+                match state.scripts.get(&sha).cloned() {
+                    Some(script) => run_script(&script, &args[3], &args[4..], state, clock)
+                        .unwrap_or_else(|err| {
+                            // error replies are a single line
+                            let err = err.to_string().replace(['\r', '\n'], " ");
+                            format!("-ERR {}\r\n", err).into_bytes()
+                        }),
+                    None => b"-NOSCRIPT No matching script.\r\n".to_vec(),
+                }
+            }
+            _ => b"-ERR unknown command\r\n".to_vec(),
+        }
+    }
+
+    fn run_script(
+        script: &str,
+        key: &[u8],
+        argv: &[Vec<u8>],
+        state: &mut State,
+        clock: &MockClock,
+    ) -> mlua::Result<Vec<u8>> {
+        let lua = Lua::new();
+        let globals = lua.globals();
+        globals.set("KEYS", lua.create_sequence_from([lua.create_string(key)?])?)?;
+        let argv = argv
+            .iter()
+            .map(|arg| lua.create_string(arg))
+            .collect::<mlua::Result<Vec<_>>>()?;
+        globals.set("ARGV", lua.create_sequence_from(argv)?)?;
+        let replicate_commands = Cell::new(false);
+        let called_time = Cell::new(false);
+        let reply = lua.scope(|scope| {
+            let redis = lua.create_table()?;
+            redis.set(
+                "replicate_commands",
+                scope.create_function_mut(|_, ()| {
+                    replicate_commands.set(true);
+                    Ok(true)
+                })?,
+            )?;
+            redis.set(
+                "call",
+                scope.create_function_mut(|lua, args: Variadic<Value>| {
+                    let args = args
+                        .into_iter()
+                        .map(|arg| Ok(lua.coerce_string(arg)?.unwrap().as_bytes().to_vec()))
+                        .collect::<mlua::Result<Vec<_>>>()?;
+                    match args[0].to_ascii_uppercase().as_slice() {
+                        b"TIME" => {
+                            called_time.set(true);
+                            let micros = unix_nanos(clock.system_time()).unwrap() / 1000;
+                            let time = [micros / 1_000_000, micros % 1_000_000];
+                            Ok(Value::Table(
+                                lua.create_sequence_from(time.map(|part| part.to_string()))?,
+                            ))
+                        }
+                        b"GET" => Ok(match state.values.get(&args[1]) {
+                            Some((value, _)) => Value::String(lua.create_string(value)?),
+                            None => Value::Boolean(false),
+                        }),
+                        b"SET" => {
+                            if called_time.get() && !replicate_commands.get() {
+                                return Err(mlua::Error::runtime(
+                                    "Write commands not allowed after non deterministic commands",
+                                ));
+                            }
+                            let expires = match args.get(3).map(|arg| arg.to_ascii_uppercase()) {
+                                Some(option) if option == b"PX" => {
+                                    let millis =
+                                        parse_u64(&args[4]).map_err(mlua::Error::external)?;
+                                    Some(clock.system_time() + Duration::from_millis(millis))
+                                }
+                                _ => None,
+                            };
+                            state
+                                .values
+                                .insert(args[1].clone(), (args[2].clone(), expires));
+                            let ok = lua.create_table()?;
+                            ok.set("ok", "OK")?;
+                            Ok(Value::Table(ok))
+                        }
+                        _ => Err(mlua::Error::runtime("unknown command")),
+                    }
+                })?,
+            )?;
+            lua.globals().set("redis", redis)?;
+            lua.load(script).eval::<Value>()
+        })?;
+        // the server's conversion of a script's return value
+        Ok(match reply {
+            Value::Integer(value) => format!(":{}\r\n", value).into_bytes(),
+            Value::Number(value) => format!(":{}\r\n", value as i64).into_bytes(),
+            Value::String(value) => bulk(&value.as_bytes()),
+            Value::Boolean(true) => integer(1),
+            _ => b"$-1\r\n".to_vec(),
+        })
+    }
+
+    fn bulk(value: &[u8]) -> Vec<u8> {
+        let mut reply = format!("${}\r\n", value.len()).into_bytes();
+        reply.extend_from_slice(value);
+        reply.extend_from_slice(b"\r\n");
+        reply
+    }
+
+    fn integer(value: u64) -> Vec<u8> {
+        format!(":{}\r\n", value).into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod test_resp {
+    use super::*;
+
+    #[test]
+    fn writes_commands_as_bulk_arrays() {
+        let mut buf = Vec::new();
+        write_command(&mut buf, &[b"GET", b"key"]).unwrap();
+        assert_eq!(buf, b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n");
+    }
+
+    #[test]
+    fn reads_replies() {
+        let mut reply: &[u8] = b"*4\r\n+OK\r\n:-3\r\n$2\r\nhi\r\n$-1\r\n";
+        assert_eq!(
+            read_value(&mut reply).unwrap(),
+            RespValue::Array(Some(vec![
+                RespValue::Simple("OK".to_owned()),
+                RespValue::Integer(-3),
+                RespValue::Bulk(Some(b"hi".to_vec())),
+                RespValue::Bulk(None),
+            ]))
+        );
+    }
+
+    #[test]
+    fn error_replies_are_errors() {
+        let mut reply: &[u8] = b"-NOSCRIPT No matching script.\r\n";
+        let err = read_value(&mut reply).unwrap_err();
+        assert_eq!(err.to_string(), "NOSCRIPT No matching script.");
+    }
+}
+
+#[cfg(test)]
+mod test_redis_token_bucket {
+    use super::stand_in::StandIn;
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::store::StoreTokenBucket;
+
+    fn bucket(server: &StandIn, clock: &MockClock) -> RedisTokenBucket<MockClock> {
+        let connection = RedisConnection::connect(server.addr).unwrap();
+        RedisTokenBucket::with_clock(connection, Duration::from_millis(10), 3, clock.clone())
+            .unwrap()
+    }
+
+    #[test]
+    fn instances_share_one_limit() {
+        let clock = MockClock::new();
+        let server = StandIn::start(clock.clone());
+        let first = bucket(&server, &clock);
+        let second = bucket(&server, &clock);
+        assert!(first.try_take_n("customer", 2).is_ok());
+        assert!(second.try_take_n("customer", 2).is_err());
+        assert!(second.try_take("customer").is_ok());
+        assert!(first.try_take("other").is_ok());
+    }
+
+    #[test]
+    fn reports_same_retry_after_as_local_bucket() {
+        let clock = MockClock::new();
+        let server = StandIn::start(clock.clone());
+        let remote = bucket(&server, &clock);
+        let mut local = crate::token_bucket::TokenBucket::with_clock(
+            Duration::from_millis(10),
+            3,
+            3,
+            clock.clone(),
+        )
+        .unwrap();
+        remote.try_take_n("a", 3).unwrap();
+        local.try_take_n(3).unwrap();
+        clock.advance(Duration::from_millis(4));
+        let remote_not_until = remote.check_n("a", 2).unwrap().unwrap_err();
+        let local_not_until = local.check_n(2).unwrap().unwrap_err();
+        assert_eq!(remote_not_until, local_not_until);
+        assert_eq!(remote_not_until.wait, Duration::from_millis(16));
+    }
+
+    #[test]
+    fn rejects_more_than_capacity_without_calling_server() {
+        let clock = MockClock::new();
+        let server = StandIn::start(clock.clone());
+        let tb = bucket(&server, &clock);
+        match tb.try_take_n("a", 4) {
+            Err(StoreError::Bucket(TokenBucketError::CapacityOverflow)) => {}
+            result => panic!("unexpected {:?}", result),
+        }
+        assert_eq!(server.get("a"), None);
+    }
+
+    #[test]
+    fn keys_expire_once_full() {
+        let clock = MockClock::new();
+        let server = StandIn::start(clock.clone());
+        let tb = bucket(&server, &clock);
+        tb.try_take("a").unwrap();
+        assert!(server.get("a").is_some());
+        clock.advance(Duration::from_millis(11));
+        tb.try_take("b").unwrap();
+        assert_eq!(server.get("a"), None);
+    }
+
+    #[test]
+    fn expiry_rounds_up_to_whole_milliseconds() {
+        let clock = MockClock::new();
+        let server = StandIn::start(clock.clone());
+        let tb = bucket(&server, &clock);
+        tb.try_take("a").unwrap();
+        assert_eq!(server.pttl("a", &clock), Some(Duration::from_millis(11)));
+        clock.advance(Duration::from_micros(1500));
+        tb.try_take("a").unwrap();
+        // full again 18.5ms from now
+        assert_eq!(server.pttl("a", &clock), Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn writes_after_time_need_replicate_commands() {
+        let clock = MockClock::new();
+        let server = StandIn::start(clock.clone());
+        let connection = RedisConnection::connect(server.addr).unwrap();
+        let script = TAKE_SCRIPT.replace("redis.replicate_commands()\n", "");
+        let err = connection
+            .eval(script.leak(), "a", &[b"30000", b"10000"])
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("Write commands not allowed"),
+            "{}",
+            err
+        );
+        assert_eq!(
+            connection
+                .eval(TAKE_SCRIPT, "a", &[b"30000", b"10000"])
+                .unwrap(),
+            RespValue::Integer(0)
+        );
+    }
+
+    #[test]
+    fn reloads_scripts_the_server_lost() {
+        let clock = MockClock::new();
+        let server = StandIn::start(clock.clone());
+        let tb = bucket(&server, &clock);
+        tb.try_take("a").unwrap();
+        server.flush_scripts();
+        tb.try_take("a").unwrap();
+        assert!(tb.try_take_n("a", 2).is_err());
+    }
+
+    #[test]
+    fn works_as_rate_limit_store() {
+        let clock = MockClock::new();
+        let server = StandIn::start(clock.clone());
+        let connection = RedisConnection::connect(server.addr).unwrap();
+        assert_eq!(connection.get("a").unwrap(), None);
+        assert!(connection.compare_and_set("a", None, 5).unwrap());
+        assert!(!connection.compare_and_set("a", None, 6).unwrap());
+        assert!(connection.compare_and_set("a", Some(5), 6).unwrap());
+        assert_eq!(connection.get("a").unwrap(), Some(6));
+
+        let tb =
+            StoreTokenBucket::with_clock(connection, Duration::from_millis(10), 2, clock).unwrap();
+        assert!(tb.try_take_n("b", 2).is_ok());
+        assert!(tb.try_take("b").is_err());
+    }
+}
+
+/// Runs against a real redis-server, REDIS_ADDR defaults to 127.0.0.1:6379
+#[cfg(test)]
+mod test_redis_server {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::time::Instant;
+
+    fn connect() -> RedisConnection {
+        let addr = env::var("REDIS_ADDR").unwrap_or_else(|_| "127.0.0.1:6379".to_owned());
+        RedisConnection::connect(addr).expect("redis-server is running")
+    }
+
+    #[test]
+    #[ignore = "needs a redis-server"]
+    fn take_script_enforces_the_limit() {
+        let key = format!("token_bucket_test_{}", process::id());
+        let tb = RedisTokenBucket::new(connect(), Duration::from_millis(100), 3).unwrap();
+        let start = Instant::now();
+        assert!(tb.try_take_n(&key, 3).is_ok());
+        let not_until = tb.check_n(&key, 2).unwrap().unwrap_err();
+        assert!(not_until.wait <= Duration::from_millis(200));
+        assert!(not_until.wait + start.elapsed() >= Duration::from_millis(200));
+        match connect().command(&[b"PTTL", key.as_bytes()]).unwrap() {
+            RespValue::Integer(pttl) => assert!((1..=301).contains(&pttl)),
+            value => panic!("unexpected {:?}", value),
+        }
+        std::thread::sleep(not_until.wait);
+        assert!(tb.try_take_n(&key, 2).is_ok());
+        connect().command(&[b"DEL", key.as_bytes()]).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a redis-server"]
+    fn compare_and_set_script() {
+        let key = format!("token_bucket_cas_test_{}", process::id());
+        let connection = connect();
+        assert!(connection.compare_and_set(&key, None, 5).unwrap());
+        assert!(!connection.compare_and_set(&key, None, 6).unwrap());
+        assert!(connection.compare_and_set(&key, Some(5), 6).unwrap());
+        assert_eq!(connection.get(&key).unwrap(), Some(6));
+        connection.command(&[b"DEL", key.as_bytes()]).unwrap();
+    }
+}
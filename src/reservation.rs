@@ -0,0 +1,132 @@
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::TokenBucketError;
+use crate::token_bucket::TokenBucket;
+
+/// Tokens already taken from a bucket that may only be used from ready_at
+#[derive(Clone, Debug)]
+pub struct Reservation<C = SystemClock> {
+    ready_at: Instant,
+    tokens: u64,
+    bucket_id: u64,
+    clock: C,
+}
+impl<C: Clock> Reservation<C> {
+    pub(crate) fn new(ready_at: Instant, tokens: u64, bucket_id: u64, clock: C) -> Reservation<C> {
+        Reservation {
+            ready_at,
+            tokens,
+            bucket_id,
+            clock,
+        }
+    }
+
+    pub fn ready_at(&self) -> Instant {
+        self.ready_at
+    }
+
+    pub fn tokens(&self) -> u64 {
+        self.tokens
+    }
+
+    /// Zero once the tokens may be used
+    pub fn delay(&self) -> Duration {
+        self.ready_at.saturating_duration_since(self.clock.now())
+    }
+
+    /// Gives the tokens back to the bucket they came from, any other bucket is rejected.
+    /// Does nothing once ready since the tokens count as used.
+    pub fn cancel(self, bucket: &mut TokenBucket<C>) -> Result<(), TokenBucketError> {
+        if bucket.id() != self.bucket_id {
+            return Err(TokenBucketError::ForeignReservation);
+        }
+        if self.delay().is_zero() {
+            return Ok(());
+        }
+        bucket.refund(self.tokens)
+    }
+}
+
+#[cfg(test)]
+mod test_reservation {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn bucket(clock: &MockClock, initial: u64) -> TokenBucket<MockClock> {
+        TokenBucket::with_clock(Duration::from_millis(10), 5, initial, clock.clone()).unwrap()
+    }
+
+    #[test]
+    fn ready_now_when_tokens_available() {
+        let clock = MockClock::new();
+        let mut tb = bucket(&clock, 5);
+        let reservation = tb.reserve(3).unwrap();
+        assert_eq!(reservation.delay(), Duration::ZERO);
+        assert_eq!(reservation.ready_at(), clock.now());
+        assert_eq!(reservation.tokens(), 3);
+        assert_eq!(tb.available(), 2);
+    }
+
+    #[test]
+    fn goes_into_debt() {
+        let clock = MockClock::new();
+        let mut tb = bucket(&clock, 1);
+        let first = tb.reserve(3).unwrap();
+        assert_eq!(first.delay(), Duration::from_millis(20));
+        let second = tb.reserve(2).unwrap();
+        assert_eq!(second.delay(), Duration::from_millis(40));
+        assert_eq!(tb.available(), 0);
+        assert!(tb.try_take().is_err());
+        clock.advance(Duration::from_millis(20));
+        assert_eq!(first.delay(), Duration::ZERO);
+        clock.advance(Duration::from_millis(30));
+        assert_eq!(tb.try_take(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_more_than_capacity() {
+        let clock = MockClock::new();
+        let mut tb = bucket(&clock, 5);
+        assert_eq!(
+            tb.reserve(6).err().unwrap(),
+            TokenBucketError::CapacityOverflow
+        );
+        assert_eq!(tb.available(), 5);
+    }
+
+    #[test]
+    fn cancel_returns_tokens() {
+        let clock = MockClock::new();
+        let mut tb = bucket(&clock, 2);
+        let reservation = tb.reserve(4).unwrap();
+        assert_eq!(reservation.delay(), Duration::from_millis(20));
+        reservation.cancel(&mut tb).unwrap();
+        assert_eq!(tb.available(), 2);
+    }
+
+    #[test]
+    fn cancel_after_ready_keeps_tokens_used() {
+        let clock = MockClock::new();
+        let mut tb = bucket(&clock, 0);
+        let reservation = tb.reserve(1).unwrap();
+        clock.advance(Duration::from_millis(10));
+        reservation.cancel(&mut tb).unwrap();
+        assert_eq!(tb.available(), 0);
+    }
+
+    #[test]
+    fn cancel_into_another_bucket_is_rejected() {
+        let clock = MockClock::new();
+        let mut tb = bucket(&clock, 2);
+        let mut other = bucket(&clock, 2);
+        let reservation = tb.reserve(2).unwrap();
+        other.try_take_n(2).unwrap();
+        assert_eq!(
+            reservation.cancel(&mut other),
+            Err(TokenBucketError::ForeignReservation)
+        );
+        assert_eq!(other.available(), 0);
+        assert_eq!(tb.available(), 0);
+    }
+}
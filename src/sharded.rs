@@ -0,0 +1,150 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+use crate::builder::TokenBucketBuilder;
+use crate::clock::{Clock, SystemClock};
+use crate::error::{NotUntil, TokenBucketError};
+use crate::keyed::KeyedTokenBuckets;
+
+#[cfg(test)]
+use std::sync::Arc;
+#[cfg(test)]
+use std::time::Duration;
+
+/// KeyedTokenBuckets split over lock-striped shards so keys on different shards
+/// never contend. Shared through &self.
+pub struct ShardedTokenBuckets<K, C = SystemClock> {
+    shards: Box<[Mutex<KeyedTokenBuckets<K, C>>]>,
+    hasher: RandomState,
+}
+impl<K: Hash + Eq + Clone, C: Clock + Clone> ShardedTokenBuckets<K, C> {
+    /// Four shards per available core
+    pub fn new(builder: TokenBucketBuilder<C>) -> Result<Self, TokenBucketError> {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        ShardedTokenBuckets::with_shards(builder, cores * 4)
+    }
+
+    /// At least one shard is always used
+    pub fn with_shards(
+        builder: TokenBucketBuilder<C>,
+        shards: usize,
+    ) -> Result<Self, TokenBucketError> {
+        let shards = (0..shards.max(1))
+            .map(|_| KeyedTokenBuckets::new(builder.clone()).map(Mutex::new))
+            .collect::<Result<_, _>>()?;
+        Ok(ShardedTokenBuckets {
+            shards,
+            hasher: RandomState::new(),
+        })
+    }
+
+    pub fn try_take(&self, key: &K) -> Result<(), TokenBucketError> {
+        self.try_take_n(key, 1)
+    }
+
+    pub fn try_take_n(&self, key: &K, n: u64) -> Result<(), TokenBucketError> {
+        self.shard(key).try_take_n(key, n)
+    }
+
+    pub fn check(&self, key: &K) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        self.check_n(key, 1)
+    }
+
+    pub fn check_n(&self, key: &K, n: u64) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        self.shard(key).check_n(key, n)
+    }
+
+    pub fn available(&self, key: &K) -> Result<u64, TokenBucketError> {
+        self.shard(key).available(key)
+    }
+
+    /// Locks one shard at a time
+    pub fn evict_full(&self) {
+        for shard in self.shards.iter() {
+            lock(shard).evict_full();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| lock(shard).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| lock(shard).is_empty())
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard(&self, key: &K) -> MutexGuard<'_, KeyedTokenBuckets<K, C>> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        lock(&self.shards[index])
+    }
+}
+
+// A panic can't leave a bucket half updated, so a poisoned shard is still usable
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod test_try_take {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn builder(clock: &MockClock) -> TokenBucketBuilder<MockClock> {
+        TokenBucketBuilder::new()
+            .rate(1, Duration::from_millis(10))
+            .burst(2)
+            .clock(clock.clone())
+    }
+
+    #[test]
+    fn keys_have_separate_buckets() {
+        let clock = MockClock::new();
+        let sharded = ShardedTokenBuckets::with_shards(builder(&clock), 4).unwrap();
+        assert_eq!(sharded.shard_count(), 4);
+        assert_eq!(sharded.try_take_n(&"a", 2), Ok(()));
+        assert!(sharded.try_take(&"a").is_err());
+        assert_eq!(sharded.try_take_n(&"b", 2), Ok(()));
+        clock.advance(Duration::from_millis(10));
+        assert_eq!(sharded.try_take(&"a"), Ok(()));
+        assert_eq!(sharded.available(&"b"), Ok(1));
+        assert_eq!(sharded.len(), 2);
+        clock.advance(Duration::from_millis(20));
+        sharded.evict_full();
+        assert!(sharded.is_empty());
+    }
+
+    #[test]
+    fn uses_at_least_one_shard() {
+        let clock = MockClock::new();
+        let sharded = ShardedTokenBuckets::<u32, _>::with_shards(builder(&clock), 0).unwrap();
+        assert_eq!(sharded.shard_count(), 1);
+        assert_eq!(sharded.try_take(&1), Ok(()));
+    }
+
+    #[test]
+    fn threads_share_each_key_limit() {
+        let clock = MockClock::new();
+        let builder = TokenBucketBuilder::new()
+            .rate(100, Duration::from_secs(1))
+            .clock(clock);
+        let sharded = Arc::new(ShardedTokenBuckets::new(builder).unwrap());
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let sharded = Arc::clone(&sharded);
+                thread::spawn(move || {
+                    (0..400)
+                        .filter(|i| sharded.try_take(&(i % 4)).is_ok())
+                        .count()
+                })
+            })
+            .collect();
+        let taken: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(taken, 4 * 100);
+    }
+}
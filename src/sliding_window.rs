@@ -0,0 +1,393 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::{NotUntil, TokenBucketError};
+use crate::fixed_window::fits_windows;
+use crate::limiter::{LimiterState, RateLimiter};
+use crate::token_bucket::duration_from_nanos;
+
+/// At most limit tokens in any rolling window, exact but remembers every admission.
+/// The window must fit past every admission, so huge windows are rejected.
+pub struct SlidingWindowLogLimiter<C = SystemClock> {
+    limit: u64,
+    window: Duration,
+    log: VecDeque<(Instant, u64)>,
+    count: u64,
+    clock: C,
+}
+impl SlidingWindowLogLimiter {
+    pub fn new(limit: u64, window: Duration) -> Result<SlidingWindowLogLimiter, TokenBucketError> {
+        SlidingWindowLogLimiter::with_clock(limit, window, SystemClock)
+    }
+}
+impl<C: Clock> SlidingWindowLogLimiter<C> {
+    pub fn with_clock(
+        limit: u64,
+        window: Duration,
+        clock: C,
+    ) -> Result<SlidingWindowLogLimiter<C>, TokenBucketError> {
+        validate(limit, window)?;
+        fits_windows(clock.now(), window, 1)?;
+        Ok(SlidingWindowLogLimiter {
+            limit,
+            window,
+            log: VecDeque::new(),
+            count: 0,
+            clock,
+        })
+    }
+}
+impl<C: Clock> RateLimiter for SlidingWindowLogLimiter<C> {
+    fn check_n(&mut self, n: u64) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        if n > self.limit {
+            return Err(TokenBucketError::CapacityOverflow);
+        }
+        // nothing to log, a zero-token entry would only grow the log
+        if n == 0 {
+            return Ok(Ok(()));
+        }
+        let now = self.clock.now();
+        while let Some(&(admitted, tokens)) = self.log.front() {
+            if admitted + self.window > now {
+                break;
+            }
+            self.log.pop_front();
+            self.count -= tokens;
+        }
+        if n <= self.limit - self.count {
+            self.log.push_back((fits_windows(now, self.window, 1)?, n));
+            self.count += n;
+            return Ok(Ok(()));
+        }
+        let mut to_expire = n - (self.limit - self.count);
+        for &(admitted, tokens) in &self.log {
+            to_expire = to_expire.saturating_sub(tokens);
+            if to_expire == 0 {
+                let earliest = admitted + self.window;
+                return Ok(Err(NotUntil {
+                    earliest,
+                    wait: earliest - now,
+                }));
+            }
+        }
+        unreachable!("the log holds count tokens")
+    }
+
+    fn wait_n(&mut self, n: u64) -> Result<(), TokenBucketError> {
+        while let Err(not_until) = self.check_n(n)? {
+            self.clock.sleep(not_until.wait);
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> LimiterState {
+        let now = self.clock.now();
+        let count: u64 = self
+            .log
+            .iter()
+            .filter(|(admitted, _)| *admitted + self.window > now)
+            .map(|(_, tokens)| tokens)
+            .sum();
+        LimiterState {
+            available: self.limit - count,
+            capacity: self.limit,
+        }
+    }
+}
+
+/// Approximates a rolling window by weighting the previous fixed window's count
+/// by how much of it still overlaps, constant memory unlike the log.
+/// Two windows must fit past the current one's start, so huge windows are rejected.
+pub struct SlidingWindowCounterLimiter<C = SystemClock> {
+    limit: u64,
+    window: Duration,
+    window_start: Instant,
+    previous: u64,
+    current: u64,
+    clock: C,
+}
+impl SlidingWindowCounterLimiter {
+    pub fn new(
+        limit: u64,
+        window: Duration,
+    ) -> Result<SlidingWindowCounterLimiter, TokenBucketError> {
+        SlidingWindowCounterLimiter::with_clock(limit, window, SystemClock)
+    }
+}
+impl<C: Clock> SlidingWindowCounterLimiter<C> {
+    pub fn with_clock(
+        limit: u64,
+        window: Duration,
+        clock: C,
+    ) -> Result<SlidingWindowCounterLimiter<C>, TokenBucketError> {
+        validate(limit, window)?;
+        // counts are weighted by nanos into the window
+        window
+            .as_nanos()
+            .checked_mul(u128::from(limit))
+            .ok_or(TokenBucketError::CapacityOverflow)?;
+        Ok(SlidingWindowCounterLimiter {
+            limit,
+            window,
+            window_start: fits_windows(clock.now(), window, 2)?,
+            previous: 0,
+            current: 0,
+            clock,
+        })
+    }
+
+    fn roll_window(&mut self, now: Instant) -> Result<(), TokenBucketError> {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        let windows = elapsed.as_nanos() / self.window.as_nanos();
+        if windows > 0 {
+            let skipped = duration_from_nanos(windows * self.window.as_nanos())
+                .expect("no more than the elapsed time");
+            self.window_start = fits_windows(self.window_start + skipped, self.window, 2)?;
+            self.previous = if windows == 1 { self.current } else { 0 };
+            self.current = 0;
+        }
+        Ok(())
+    }
+
+    // Nanos into a window at which previous no longer weighs more than allowed
+    fn overlap_ends(&self, previous: u64, allowed: u64) -> u128 {
+        let window = self.window.as_nanos();
+        window - u128::from(allowed) * window / u128::from(previous)
+    }
+}
+impl<C: Clock> RateLimiter for SlidingWindowCounterLimiter<C> {
+    fn check_n(&mut self, n: u64) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        if n > self.limit {
+            return Err(TokenBucketError::CapacityOverflow);
+        }
+        let now = self.clock.now();
+        self.roll_window(now)?;
+        let window = self.window.as_nanos();
+        let elapsed = (now - self.window_start).as_nanos();
+        let weighted_previous = u128::from(self.previous) * (window - elapsed) / window;
+        if weighted_previous + u128::from(self.current) + u128::from(n) <= u128::from(self.limit) {
+            self.current += n;
+            return Ok(Ok(()));
+        }
+        let since_window_start = if n <= self.limit - self.current {
+            self.overlap_ends(self.previous, self.limit - self.current - n)
+        } else {
+            window + self.overlap_ends(self.current, self.limit - n)
+        };
+        let earliest = self.window_start
+            + duration_from_nanos(since_window_start).expect("at most two windows");
+        Ok(Err(NotUntil {
+            earliest,
+            wait: earliest.saturating_duration_since(now),
+        }))
+    }
+
+    fn wait_n(&mut self, n: u64) -> Result<(), TokenBucketError> {
+        while let Err(not_until) = self.check_n(n)? {
+            self.clock.sleep(not_until.wait);
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> LimiterState {
+        let window = self.window.as_nanos();
+        let elapsed = self
+            .clock
+            .now()
+            .saturating_duration_since(self.window_start)
+            .as_nanos();
+        let (previous, current, elapsed) = match elapsed / window {
+            0 => (self.previous, self.current, elapsed),
+            1 => (self.current, 0, elapsed - window),
+            _ => (0, 0, 0),
+        };
+        let weighted_previous = u128::from(previous) * (window - elapsed) / window;
+        let used = (weighted_previous as u64).saturating_add(current);
+        LimiterState {
+            available: self.limit.saturating_sub(used),
+            capacity: self.limit,
+        }
+    }
+}
+
+fn validate(limit: u64, window: Duration) -> Result<(), TokenBucketError> {
+    if window.is_zero() {
+        return Err(TokenBucketError::InvalidInterval);
+    }
+    if limit == 0 {
+        return Err(TokenBucketError::InvalidCapacity);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_log {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn limiter(clock: &MockClock) -> SlidingWindowLogLimiter<MockClock> {
+        SlidingWindowLogLimiter::with_clock(3, Duration::from_secs(1), clock.clone()).unwrap()
+    }
+
+    #[test]
+    fn allows_limit_in_any_rolling_window() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        assert_eq!(limiter.check(), Ok(Ok(())));
+        clock.advance(Duration::from_millis(600));
+        assert_eq!(limiter.check_n(2), Ok(Ok(())));
+        let not_until = limiter.check().unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_millis(400));
+        clock.advance(not_until.wait);
+        assert_eq!(limiter.check(), Ok(Ok(())));
+        let not_until = limiter.check_n(2).unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_millis(600));
+    }
+
+    #[test]
+    fn no_burst_at_window_edges() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        clock.advance(Duration::from_millis(900));
+        assert_eq!(limiter.check_n(3), Ok(Ok(())));
+        clock.advance(Duration::from_millis(200));
+        assert!(limiter.check().unwrap().is_err());
+    }
+
+    #[test]
+    fn rejects_more_than_limit() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        assert_eq!(limiter.check_n(4), Err(TokenBucketError::CapacityOverflow));
+    }
+
+    #[test]
+    fn counts_up_to_u64_max() {
+        let clock = MockClock::new();
+        let mut limiter =
+            SlidingWindowLogLimiter::with_clock(u64::MAX, Duration::from_secs(1), clock.clone())
+                .unwrap();
+        assert_eq!(limiter.check_n(u64::MAX), Ok(Ok(())));
+        let not_until = limiter.check().unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_secs(1));
+        assert_eq!(limiter.state().available, 0);
+    }
+
+    #[test]
+    fn zero_tokens_are_not_logged() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        for _ in 0..10 {
+            assert_eq!(limiter.check_n(0), Ok(Ok(())));
+        }
+        assert!(limiter.log.is_empty());
+    }
+
+    // Longest whole-second window that still fits past the clock's now
+    fn largest_window(clock: &MockClock) -> Duration {
+        let (mut fits, mut overflows) = (0, u64::MAX);
+        while overflows - fits > 1 {
+            let secs = fits + (overflows - fits) / 2;
+            match fits_windows(clock.now(), Duration::from_secs(secs), 1) {
+                Ok(_) => fits = secs,
+                Err(_) => overflows = secs,
+            }
+        }
+        Duration::from_secs(fits)
+    }
+
+    #[test]
+    fn rejects_admissions_whose_window_would_overflow() {
+        let clock = MockClock::new();
+        let window = largest_window(&clock);
+        let mut limiter = SlidingWindowLogLimiter::with_clock(3, window, clock.clone()).unwrap();
+        assert_eq!(limiter.check(), Ok(Ok(())));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.check_n(1), Err(TokenBucketError::CapacityOverflow));
+        assert_eq!(limiter.state().available, 2);
+    }
+}
+
+#[cfg(test)]
+mod test_counter {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn limiter(clock: &MockClock) -> SlidingWindowCounterLimiter<MockClock> {
+        SlidingWindowCounterLimiter::with_clock(4, Duration::from_secs(1), clock.clone()).unwrap()
+    }
+
+    #[test]
+    fn weights_previous_window() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        assert_eq!(limiter.check_n(4), Ok(Ok(())));
+        clock.advance(Duration::from_millis(1250));
+        // 4 * 0.75 = 3 of the previous window still count
+        assert_eq!(limiter.check(), Ok(Ok(())));
+        let not_until = limiter.check().unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_millis(250));
+        clock.advance(not_until.wait);
+        assert_eq!(limiter.check(), Ok(Ok(())));
+    }
+
+    #[test]
+    fn waits_into_next_window_when_current_is_full() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(limiter.check_n(4), Ok(Ok(())));
+        let not_until = limiter.check_n(2).unwrap().unwrap_err();
+        // next window starts at 1s, 4 * (1 - 0.5) = 2 leaves room for 2
+        assert_eq!(not_until.wait, Duration::from_millis(1400));
+        clock.advance(not_until.wait);
+        assert_eq!(limiter.check_n(2), Ok(Ok(())));
+    }
+
+    #[test]
+    fn forgets_after_two_windows() {
+        let clock = MockClock::new();
+        let mut limiter = limiter(&clock);
+        assert_eq!(limiter.check_n(4), Ok(Ok(())));
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(limiter.check_n(4), Ok(Ok(())));
+    }
+
+    #[test]
+    fn counts_up_to_u64_max() {
+        let clock = MockClock::new();
+        let mut limiter = SlidingWindowCounterLimiter::with_clock(
+            u64::MAX,
+            Duration::from_secs(1),
+            clock.clone(),
+        )
+        .unwrap();
+        assert_eq!(limiter.check_n(u64::MAX), Ok(Ok(())));
+        let not_until = limiter.check().unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_nanos(1_000_000_001));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.state().available, 0);
+        assert!(limiter.check().unwrap().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        assert_eq!(
+            SlidingWindowCounterLimiter::new(0, Duration::from_secs(1)).err(),
+            Some(TokenBucketError::InvalidCapacity)
+        );
+        assert_eq!(
+            SlidingWindowLogLimiter::new(1, Duration::ZERO).err(),
+            Some(TokenBucketError::InvalidInterval)
+        );
+        assert_eq!(
+            SlidingWindowLogLimiter::new(1, Duration::MAX).err(),
+            Some(TokenBucketError::CapacityOverflow)
+        );
+        assert_eq!(
+            SlidingWindowCounterLimiter::new(1, Duration::MAX).err(),
+            Some(TokenBucketError::CapacityOverflow)
+        );
+    }
+}
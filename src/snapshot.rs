@@ -0,0 +1,113 @@
+use std::time::{Duration, SystemTime};
+
+/// Bucket state that outlives the process.
+/// last_refreshed is wall-clock time so tokens keep refilling while the bucket is saved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TokenBucketSnapshot {
+    pub refill_interval: Duration,
+    pub capacity: u64,
+    pub last_refreshed: SystemTime,
+}
+
+#[cfg(test)]
+mod test_snapshot {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::error::TokenBucketError;
+    use crate::token_bucket::TokenBucket;
+
+    fn bucket(clock: &MockClock) -> TokenBucket<MockClock> {
+        TokenBucket::with_clock(Duration::from_millis(10), 5, 0, clock.clone()).unwrap()
+    }
+
+    #[test]
+    fn round_trips_tokens_and_progress() {
+        let clock = MockClock::new();
+        let tb = bucket(&clock);
+        clock.advance(Duration::from_millis(25));
+        let wall_now = SystemTime::now();
+        let snapshot = tb.snapshot_at(wall_now);
+        assert_eq!(snapshot.capacity, 5);
+        assert_eq!(snapshot.refill_interval, Duration::from_millis(10));
+
+        let restored =
+            TokenBucket::restore_with_clock(snapshot, MockClock::new(), wall_now).unwrap();
+        assert_eq!(restored.available(), 2);
+        assert_eq!(
+            restored.time_until_available(3),
+            Ok(Duration::from_millis(5))
+        );
+    }
+
+    #[test]
+    fn refills_while_saved() {
+        let clock = MockClock::new();
+        let tb = bucket(&clock);
+        let wall_now = SystemTime::now();
+        let snapshot = tb.snapshot_at(wall_now);
+        let later = wall_now + Duration::from_millis(30);
+        let restored = TokenBucket::restore_with_clock(snapshot, MockClock::new(), later).unwrap();
+        assert_eq!(restored.available(), 3);
+        let much_later = wall_now + Duration::from_secs(3600);
+        let restored =
+            TokenBucket::restore_with_clock(snapshot, MockClock::new(), much_later).unwrap();
+        assert_eq!(restored.available(), 5);
+    }
+
+    #[test]
+    fn keeps_debt() {
+        let clock = MockClock::new();
+        let mut tb = bucket(&clock);
+        tb.reserve(2).unwrap();
+        let wall_now = SystemTime::now();
+        let snapshot = tb.snapshot_at(wall_now);
+        let restored =
+            TokenBucket::restore_with_clock(snapshot, MockClock::new(), wall_now).unwrap();
+        assert_eq!(
+            restored.time_until_available(1),
+            Ok(Duration::from_millis(30))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        let snapshot = TokenBucketSnapshot {
+            refill_interval: Duration::ZERO,
+            capacity: 1,
+            last_refreshed: SystemTime::now(),
+        };
+        assert_eq!(
+            TokenBucket::restore(snapshot).err(),
+            Some(TokenBucketError::InvalidInterval)
+        );
+        let snapshot = TokenBucketSnapshot {
+            refill_interval: Duration::from_secs(1),
+            capacity: 0,
+            ..snapshot
+        };
+        assert_eq!(
+            TokenBucket::restore(snapshot).err(),
+            Some(TokenBucketError::InvalidCapacity)
+        );
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod test_serde {
+    use super::*;
+    use crate::token_bucket::TokenBucket;
+
+    #[test]
+    fn round_trips_through_json() {
+        let mut tb = TokenBucket::new(Duration::from_secs(1), 5, 5).unwrap();
+        tb.try_take_n(2).unwrap();
+        let snapshot = tb.snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let deserialized: TokenBucketSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, snapshot);
+        let restored = TokenBucket::restore(deserialized).unwrap();
+        assert_eq!(restored.available(), 3);
+        assert_eq!(restored.capacity(), 5);
+    }
+}
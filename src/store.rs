@@ -0,0 +1,497 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::{NotUntil, TokenBucketError};
+
+#[cfg(test)]
+use crate::clock::MockClock;
+
+/// Shared home of the one timestamp a bucket keys off, in nanos since the unix epoch
+pub trait RateLimitStore {
+    type Error;
+
+    fn get(&self, key: &str) -> Result<Option<u64>, Self::Error>;
+
+    /// Sets key to new only if it still holds current, None meaning absent
+    fn compare_and_set(
+        &self,
+        key: &str,
+        current: Option<u64>,
+        new: u64,
+    ) -> Result<bool, Self::Error>;
+}
+impl<S: RateLimitStore + ?Sized> RateLimitStore for &S {
+    type Error = S::Error;
+
+    fn get(&self, key: &str) -> Result<Option<u64>, S::Error> {
+        (**self).get(key)
+    }
+
+    fn compare_and_set(&self, key: &str, current: Option<u64>, new: u64) -> Result<bool, S::Error> {
+        (**self).compare_and_set(key, current, new)
+    }
+}
+impl<S: RateLimitStore + ?Sized> RateLimitStore for Arc<S> {
+    type Error = S::Error;
+
+    fn get(&self, key: &str) -> Result<Option<u64>, S::Error> {
+        (**self).get(key)
+    }
+
+    fn compare_and_set(&self, key: &str, current: Option<u64>, new: u64) -> Result<bool, S::Error> {
+        (**self).compare_and_set(key, current, new)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StoreError<E> {
+    Bucket(TokenBucketError),
+    Store(E),
+}
+
+impl<E: fmt::Display> fmt::Display for StoreError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Bucket(err) => err.fmt(f),
+            StoreError::Store(err) => write!(f, "rate limit store failed: {}", err),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for StoreError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StoreError::Bucket(err) => Some(err),
+            StoreError::Store(err) => Some(err),
+        }
+    }
+}
+
+/// TokenBucket's algorithm on unix nanos, an absent timestamp is a full bucket.
+/// Gives the new last_refreshed, or the nanos to wait for the tokens.
+pub(crate) fn next_last_refreshed(
+    last_refreshed: Option<u64>,
+    now: u64,
+    refresh_nanos: u64,
+    max_refresh_nanos: u64,
+) -> Result<u64, u64> {
+    let full = now.saturating_sub(max_refresh_nanos);
+    let effective_last_refreshed = last_refreshed.map_or(full, |last| cmp::max(last, full));
+    let new_last_refreshed = effective_last_refreshed.saturating_add(refresh_nanos);
+    if new_last_refreshed > now {
+        return Err(new_last_refreshed - now);
+    }
+    Ok(new_last_refreshed)
+}
+
+/// A TokenBucket per key whose state lives in a RateLimitStore,
+/// so every instance using the same store enforces one limit
+pub struct StoreTokenBucket<S, C = SystemClock> {
+    store: S,
+    refresh_interval_nanos: u64,
+    max_refresh_nanos: u64,
+    clock: C,
+}
+impl<S: RateLimitStore> StoreTokenBucket<S> {
+    pub fn new(
+        store: S,
+        refresh_interval: Duration,
+        max_capacity: u64,
+    ) -> Result<StoreTokenBucket<S>, TokenBucketError> {
+        StoreTokenBucket::with_clock(store, refresh_interval, max_capacity, SystemClock)
+    }
+}
+impl<S: RateLimitStore, C: Clock> StoreTokenBucket<S, C> {
+    pub fn with_clock(
+        store: S,
+        refresh_interval: Duration,
+        max_capacity: u64,
+        clock: C,
+    ) -> Result<StoreTokenBucket<S, C>, TokenBucketError> {
+        if refresh_interval.is_zero() {
+            return Err(TokenBucketError::InvalidInterval);
+        }
+        if max_capacity == 0 {
+            return Err(TokenBucketError::InvalidCapacity);
+        }
+        let refresh_interval_nanos = u64::try_from(refresh_interval.as_nanos())
+            .map_err(|_| TokenBucketError::CapacityOverflow)?;
+        let max_refresh_nanos = refresh_interval_nanos
+            .checked_mul(max_capacity)
+            .ok_or(TokenBucketError::CapacityOverflow)?;
+        Ok(StoreTokenBucket {
+            store,
+            refresh_interval_nanos,
+            max_refresh_nanos,
+            clock,
+        })
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn try_take(&self, key: &str) -> Result<(), StoreError<S::Error>> {
+        self.try_take_n(key, 1)
+    }
+
+    pub fn try_take_n(&self, key: &str, n: u64) -> Result<(), StoreError<S::Error>> {
+        self.check_n(key, n)?
+            .map_err(|not_until| StoreError::Bucket(not_until.into()))
+    }
+
+    /// Outer error when n can never fit or the store fails, inner when the tokens aren't available yet
+    pub fn check_n(&self, key: &str, n: u64) -> Result<Result<(), NotUntil>, StoreError<S::Error>> {
+        let refresh_nanos = self
+            .refresh_interval_nanos
+            .checked_mul(n)
+            .filter(|refresh_nanos| *refresh_nanos <= self.max_refresh_nanos)
+            .ok_or(StoreError::Bucket(TokenBucketError::CapacityOverflow))?;
+        loop {
+            let current = self.store.get(key).map_err(StoreError::Store)?;
+            let now = unix_nanos(self.clock.system_time()).map_err(StoreError::Bucket)?;
+            match next_last_refreshed(current, now, refresh_nanos, self.max_refresh_nanos) {
+                Ok(new) => {
+                    if self
+                        .store
+                        .compare_and_set(key, current, new)
+                        .map_err(StoreError::Store)?
+                    {
+                        return Ok(Ok(()));
+                    }
+                }
+                Err(wait) => {
+                    let wait = Duration::from_nanos(wait);
+                    return Ok(Err(NotUntil {
+                        earliest: self.clock.now() + wait,
+                        wait,
+                    }));
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn unix_nanos(time: SystemTime) -> Result<u64, TokenBucketError> {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| TokenBucketError::ClockUnderflow)?;
+    u64::try_from(since_epoch.as_nanos()).map_err(|_| TokenBucketError::CapacityOverflow)
+}
+
+/// Store local to this process, for tests and single-instance use
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    timestamps: Mutex<HashMap<String, u64>>,
+}
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+impl RateLimitStore for MemoryStore {
+    type Error = Infallible;
+
+    fn get(&self, key: &str) -> Result<Option<u64>, Infallible> {
+        let timestamps = self
+            .timestamps
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        Ok(timestamps.get(key).copied())
+    }
+
+    fn compare_and_set(
+        &self,
+        key: &str,
+        current: Option<u64>,
+        new: u64,
+    ) -> Result<bool, Infallible> {
+        let mut timestamps = self
+            .timestamps
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if timestamps.get(key).copied() != current {
+            return Ok(false);
+        }
+        timestamps.insert(key.to_owned(), new);
+        Ok(true)
+    }
+}
+
+/// Store in a file shared by every process on the host.
+/// Each line is "timestamp key", a lock on a sibling ".lock" file keeps compare_and_set
+/// atomic and updates are written to a ".tmp" file then renamed over the store,
+/// so a crash mid-write leaves the previous contents in place.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    path: PathBuf,
+    lock_path: PathBuf,
+    temp_path: PathBuf,
+}
+impl FileStore {
+    pub fn new(path: impl Into<PathBuf>) -> FileStore {
+        let path = path.into();
+        let sibling = |extension: &str| {
+            let mut sibling = path.clone().into_os_string();
+            sibling.push(extension);
+            PathBuf::from(sibling)
+        };
+        FileStore {
+            lock_path: sibling(".lock"),
+            temp_path: sibling(".tmp"),
+            path,
+        }
+    }
+
+    // The store itself is replaced on every write so the lock lives in its own file
+    fn open_lock(&self) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.lock_path)
+    }
+
+    fn read(&self) -> io::Result<HashMap<String, u64>> {
+        match File::open(&self.path) {
+            Ok(mut file) => read_timestamps(&mut file),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(err) => Err(err),
+        }
+    }
+}
+impl RateLimitStore for FileStore {
+    type Error = io::Error;
+
+    fn get(&self, key: &str) -> io::Result<Option<u64>> {
+        let lock = self.open_lock()?;
+        lock.lock_shared()?;
+        Ok(self.read()?.remove(key))
+    }
+
+    fn compare_and_set(&self, key: &str, current: Option<u64>, new: u64) -> io::Result<bool> {
+        if key.contains('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "key must not contain a newline",
+            ));
+        }
+        let lock = self.open_lock()?;
+        lock.lock()?;
+        let mut timestamps = self.read()?;
+        if timestamps.get(key).copied() != current {
+            return Ok(false);
+        }
+        timestamps.insert(key.to_owned(), new);
+        let mut contents = String::new();
+        for (key, timestamp) in &timestamps {
+            contents.push_str(&format!("{} {}\n", timestamp, key));
+        }
+        let mut temp = File::create(&self.temp_path)?;
+        temp.write_all(contents.as_bytes())?;
+        temp.sync_all()?;
+        fs::rename(&self.temp_path, &self.path)?;
+        Ok(true)
+    }
+}
+
+fn read_timestamps(file: &mut File) -> io::Result<HashMap<String, u64>> {
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    contents
+        .lines()
+        .map(|line| {
+            line.split_once(' ')
+                .and_then(|(timestamp, key)| Some((key.to_owned(), timestamp.parse().ok()?)))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed line"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test_memory_store {
+    use super::*;
+
+    #[test]
+    fn compare_and_set_only_from_current() {
+        let store = MemoryStore::new();
+        assert_eq!(store.get("a"), Ok(None));
+        assert_eq!(store.compare_and_set("a", Some(1), 2), Ok(false));
+        assert_eq!(store.compare_and_set("a", None, 2), Ok(true));
+        assert_eq!(store.compare_and_set("a", None, 3), Ok(false));
+        assert_eq!(store.compare_and_set("a", Some(2), 3), Ok(true));
+        assert_eq!(store.get("a"), Ok(Some(3)));
+    }
+}
+
+#[cfg(test)]
+mod test_file_store {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("token_bucket_{}_{}", process::id(), name))
+    }
+
+    fn remove(store: &FileStore) {
+        fs::remove_file(&store.path).unwrap();
+        fs::remove_file(&store.lock_path).unwrap();
+    }
+
+    #[test]
+    fn compare_and_set_only_from_current() {
+        let path = temp_path("compare_and_set");
+        let store = FileStore::new(&path);
+        assert_eq!(store.get("a b").unwrap(), None);
+        assert!(store.compare_and_set("a b", None, 2).unwrap());
+        assert!(!store.compare_and_set("a b", None, 3).unwrap());
+        assert!(store.compare_and_set("c", None, 7).unwrap());
+        assert!(store.compare_and_set("a b", Some(2), 3).unwrap());
+        let other = FileStore::new(&path);
+        assert_eq!(other.get("a b").unwrap(), Some(3));
+        assert_eq!(other.get("c").unwrap(), Some(7));
+        assert!(store.compare_and_set("bad\nkey", None, 1).is_err());
+        remove(&store);
+    }
+
+    #[test]
+    fn replaces_the_file_instead_of_rewriting_it() {
+        let store = FileStore::new(temp_path("replace"));
+        assert!(store.compare_and_set("a", None, 1).unwrap());
+        let mut before = File::open(&store.path).unwrap();
+        assert!(store.compare_and_set("b", None, 2).unwrap());
+        // an open handle still sees the complete previous contents
+        assert_eq!(
+            read_timestamps(&mut before).unwrap(),
+            HashMap::from([("a".to_owned(), 1)])
+        );
+        assert!(!store.temp_path.exists());
+        assert_eq!(store.get("b").unwrap(), Some(2));
+        remove(&store);
+    }
+
+    #[test]
+    fn instances_share_one_limit() {
+        let path = temp_path("shared_limit");
+        let clock = MockClock::new();
+        let first = StoreTokenBucket::with_clock(
+            FileStore::new(&path),
+            Duration::from_millis(10),
+            3,
+            clock.clone(),
+        )
+        .unwrap();
+        let second = StoreTokenBucket::with_clock(
+            FileStore::new(&path),
+            Duration::from_millis(10),
+            3,
+            clock.clone(),
+        )
+        .unwrap();
+        first.try_take_n("customer", 2).unwrap();
+        assert!(second.try_take_n("customer", 2).is_err());
+        second.try_take("customer").unwrap();
+        clock.advance(Duration::from_millis(10));
+        assert!(first.try_take("customer").is_ok());
+        remove(first.store());
+    }
+}
+
+#[cfg(test)]
+mod test_store_token_bucket {
+    use super::*;
+
+    fn bucket<'a>(
+        store: &'a MemoryStore,
+        clock: &MockClock,
+    ) -> StoreTokenBucket<&'a MemoryStore, MockClock> {
+        StoreTokenBucket::with_clock(store, Duration::from_millis(10), 3, clock.clone()).unwrap()
+    }
+
+    #[test]
+    fn new_keys_start_full() {
+        let store = MemoryStore::new();
+        let clock = MockClock::new();
+        let tb = bucket(&store, &clock);
+        assert_eq!(tb.try_take_n("a", 3), Ok(()));
+        assert!(tb.try_take("a").is_err());
+        assert_eq!(tb.try_take_n("b", 3), Ok(()));
+    }
+
+    #[test]
+    fn replicas_share_one_limit() {
+        let store = MemoryStore::new();
+        let clock = MockClock::new();
+        let first = bucket(&store, &clock);
+        let second = bucket(&store, &clock);
+        first.try_take_n("a", 2).unwrap();
+        let not_until = second.check_n("a", 2).unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_millis(10));
+        assert_eq!(not_until.earliest, clock.now() + not_until.wait);
+        clock.advance(not_until.wait);
+        assert_eq!(second.check_n("a", 2), Ok(Ok(())));
+    }
+
+    #[test]
+    fn rejects_more_than_capacity() {
+        let store = MemoryStore::new();
+        let clock = MockClock::new();
+        let tb = bucket(&store, &clock);
+        assert_eq!(
+            tb.try_take_n("a", 4),
+            Err(StoreError::Bucket(TokenBucketError::CapacityOverflow))
+        );
+        assert_eq!(store.get("a"), Ok(None));
+    }
+
+    #[test]
+    fn retries_when_compare_and_set_races() {
+        struct RacingStore {
+            inner: MemoryStore,
+            races: Mutex<u32>,
+        }
+        impl RateLimitStore for RacingStore {
+            type Error = Infallible;
+
+            fn get(&self, key: &str) -> Result<Option<u64>, Infallible> {
+                self.inner.get(key)
+            }
+
+            fn compare_and_set(
+                &self,
+                key: &str,
+                current: Option<u64>,
+                new: u64,
+            ) -> Result<bool, Infallible> {
+                let mut races = self.races.lock().unwrap();
+                if *races > 0 {
+                    *races -= 1;
+                    return Ok(false);
+                }
+                self.inner.compare_and_set(key, current, new)
+            }
+        }
+
+        let store = RacingStore {
+            inner: MemoryStore::new(),
+            races: Mutex::new(2),
+        };
+        let tb =
+            StoreTokenBucket::with_clock(store, Duration::from_millis(10), 1, MockClock::new())
+                .unwrap();
+        assert_eq!(tb.try_take("a"), Ok(()));
+        assert_eq!(*tb.store().races.lock().unwrap(), 0);
+    }
+}
@@ -1,160 +1,1037 @@
 use std::cmp;
 use std::fmt;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
-#[cfg(test)]
-use std::thread;
+use crate::builder::{interval_for_rate, TokenBucketBuilder};
+use crate::clock::{Clock, SystemClock};
+use crate::error::{NotUntil, TokenBucketError};
+use crate::reservation::Reservation;
+use crate::snapshot::TokenBucketSnapshot;
 
 /// Percision of 5ms for take
 #[derive(Clone, Copy)]
-pub struct TokenBucket {
+pub struct TokenBucket<C = SystemClock> {
     last_refreshed: Instant,
     max_refresh_duration: Duration,
     refresh_interval: Duration,
+    clock: C,
+    // Tells reservations which bucket they came from, copies share it
+    id: u64,
 }
 impl TokenBucket {
     pub fn new(
-        refresh_interval_ms: u64,
+        refresh_interval: Duration,
+        max_capacity: u64,
+        initial_capacity: u64,
+    ) -> Result<TokenBucket, TokenBucketError> {
+        TokenBucket::with_clock(
+            refresh_interval,
+            max_capacity,
+            initial_capacity,
+            SystemClock,
+        )
+    }
+
+    pub fn builder() -> TokenBucketBuilder {
+        TokenBucketBuilder::new()
+    }
+
+    pub fn restore(snapshot: TokenBucketSnapshot) -> Result<TokenBucket, TokenBucketError> {
+        TokenBucket::restore_with_clock(snapshot, SystemClock, SystemTime::now())
+    }
+}
+impl<C: Clock> TokenBucket<C> {
+    pub fn with_clock(
+        refresh_interval: Duration,
         max_capacity: u64,
         initial_capacity: u64,
-    ) -> Option<TokenBucket> {
-        if refresh_interval_ms == 0 {
-            return None;
+        clock: C,
+    ) -> Result<TokenBucket<C>, TokenBucketError> {
+        if refresh_interval.is_zero() {
+            return Err(TokenBucketError::InvalidInterval);
+        }
+        if max_capacity == 0 {
+            return Err(TokenBucketError::InvalidCapacity);
         }
 
+        let max_refresh_duration = checked_mul_duration(refresh_interval, max_capacity)
+            .ok_or(TokenBucketError::CapacityOverflow)?;
+        let now = fits_full_refresh(clock.now(), max_refresh_duration)?;
         let current_tokens_count = cmp::min(max_capacity, initial_capacity);
-        let last_refreshed = Instant::now().checked_sub(Duration::from_millis(
-            refresh_interval_ms * current_tokens_count,
-        ))?;
+        let initial_refresh_duration = checked_mul_duration(refresh_interval, current_tokens_count)
+            .ok_or(TokenBucketError::CapacityOverflow)?;
+        let last_refreshed = now
+            .checked_sub(initial_refresh_duration)
+            .ok_or(TokenBucketError::ClockUnderflow)?;
 
-        Some(TokenBucket {
-            max_refresh_duration: Duration::from_millis(refresh_interval_ms * max_capacity),
-            refresh_interval: Duration::from_millis(refresh_interval_ms),
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Ok(TokenBucket {
+            max_refresh_duration,
+            refresh_interval,
             last_refreshed,
+            clock,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
         })
     }
 
-    fn get_effective_last_refreshed(&self) -> Option<Instant> {
-        Some(cmp::max(
-            self.last_refreshed,
-            Instant::now().checked_sub(self.max_refresh_duration)?,
-        ))
+    // Moves forward from last_refreshed instead of back from now so it can't underflow
+    fn get_effective_last_refreshed(&self, now: Instant) -> Instant {
+        match now.checked_duration_since(self.last_refreshed) {
+            Some(elapsed) if elapsed > self.max_refresh_duration => {
+                self.last_refreshed + (elapsed - self.max_refresh_duration)
+            }
+            _ => self.last_refreshed,
+        }
+    }
+    // Every take writes this as last_refreshed, so it must leave room for a full refresh too
+    fn get_next_refreshed_time(&self, n: u64, now: Instant) -> Result<Instant, TokenBucketError> {
+        let refresh_duration = checked_mul_duration(self.refresh_interval, n)
+            .filter(|refresh_duration| *refresh_duration <= self.max_refresh_duration)
+            .ok_or(TokenBucketError::CapacityOverflow)?;
+        let next_refreshed = self
+            .get_effective_last_refreshed(now)
+            .checked_add(refresh_duration)
+            .ok_or(TokenBucketError::CapacityOverflow)?;
+        fits_full_refresh(next_refreshed, self.max_refresh_duration)
+    }
+
+    /// Rebuilds a snapshot taken at any earlier wall_now, counting the refill since then
+    pub fn restore_with_clock(
+        snapshot: TokenBucketSnapshot,
+        clock: C,
+        wall_now: SystemTime,
+    ) -> Result<TokenBucket<C>, TokenBucketError> {
+        let mut bucket =
+            TokenBucket::with_clock(snapshot.refill_interval, snapshot.capacity, 0, clock)?;
+        let now = bucket.clock.now();
+        bucket.last_refreshed = match wall_now.duration_since(snapshot.last_refreshed) {
+            Ok(elapsed) => now
+                .checked_sub(cmp::min(elapsed, bucket.max_refresh_duration))
+                .ok_or(TokenBucketError::ClockUnderflow)?,
+            Err(debt) => now
+                .checked_add(debt.duration())
+                .ok_or(TokenBucketError::CapacityOverflow)?,
+        };
+        fits_full_refresh(bucket.last_refreshed, bucket.max_refresh_duration)?;
+        Ok(bucket)
+    }
+
+    pub fn snapshot(&self) -> TokenBucketSnapshot {
+        self.snapshot_at(SystemTime::now())
+    }
+
+    /// wall_now is the wall-clock time matching the bucket clock's now
+    pub fn snapshot_at(&self, wall_now: SystemTime) -> TokenBucketSnapshot {
+        let now = self.clock.now();
+        let effective_last_refreshed = self.get_effective_last_refreshed(now);
+        let last_refreshed = match now.checked_duration_since(effective_last_refreshed) {
+            // before the epoch the bucket is long full anyway
+            Some(elapsed) => wall_now
+                .checked_sub(elapsed)
+                .unwrap_or(SystemTime::UNIX_EPOCH),
+            None => wall_now + (effective_last_refreshed - now),
+        };
+        TokenBucketSnapshot {
+            refill_interval: self.refresh_interval,
+            capacity: self.capacity(),
+            last_refreshed,
+        }
+    }
+
+    /// Tokens that could be taken right now
+    pub fn available(&self) -> u64 {
+        let now = self.clock.now();
+        now.checked_duration_since(self.get_effective_last_refreshed(now))
+            .map_or(0, |elapsed| {
+                (elapsed.as_nanos() / self.refresh_interval.as_nanos()) as u64
+            })
+    }
+
+    pub fn capacity(&self) -> u64 {
+        (self.max_refresh_duration.as_nanos() / self.refresh_interval.as_nanos()) as u64
     }
-    fn get_next_refreshed_time(&self) -> Option<Instant> {
-        let effective_last_refreshed = self.get_effective_last_refreshed()?;
-        let new_last_refreshed = effective_last_refreshed + self.refresh_interval;
-        Some(new_last_refreshed)
+
+    pub fn refill_interval(&self) -> Duration {
+        self.refresh_interval
     }
-    pub fn try_take(&mut self) -> Option<()> {
-        let new_last_refreshed = self.get_next_refreshed_time()?;
-        let _ = Instant::now()
-            .checked_duration_since(new_last_refreshed)?;
+
+    /// Keeps the current tokens and refill progress
+    pub fn set_rate(&mut self, tokens: u64, per: Duration) -> Result<(), TokenBucketError> {
+        self.set_refill_interval(interval_for_rate(tokens, per)?)
+    }
+
+    /// Keeps the current tokens and refill progress
+    pub fn set_refill_interval(
+        &mut self,
+        refresh_interval: Duration,
+    ) -> Result<(), TokenBucketError> {
+        if refresh_interval.is_zero() {
+            return Err(TokenBucketError::InvalidInterval);
+        }
+        let max_refresh_duration = checked_mul_duration(refresh_interval, self.capacity())
+            .ok_or(TokenBucketError::CapacityOverflow)?;
+        self.reconfigure(refresh_interval, max_refresh_duration)
+    }
+
+    /// Keeps the current tokens, clamped to the new capacity
+    pub fn set_capacity(&mut self, max_capacity: u64) -> Result<(), TokenBucketError> {
+        if max_capacity == 0 {
+            return Err(TokenBucketError::InvalidCapacity);
+        }
+        let max_refresh_duration = checked_mul_duration(self.refresh_interval, max_capacity)
+            .ok_or(TokenBucketError::CapacityOverflow)?;
+        self.reconfigure(self.refresh_interval, max_refresh_duration)
+    }
+
+    // Scales the time since last_refreshed to the new interval so whole tokens,
+    // partial refill and debt all carry over
+    fn reconfigure(
+        &mut self,
+        refresh_interval: Duration,
+        max_refresh_duration: Duration,
+    ) -> Result<(), TokenBucketError> {
+        let now = self.clock.now();
+        let effective_last_refreshed = self.get_effective_last_refreshed(now);
+        let scale = |duration: Duration| {
+            checked_scale_duration(duration, refresh_interval, self.refresh_interval)
+                .ok_or(TokenBucketError::CapacityOverflow)
+        };
+        let last_refreshed = match now.checked_duration_since(effective_last_refreshed) {
+            Some(elapsed) => now
+                .checked_sub(cmp::min(scale(elapsed)?, max_refresh_duration))
+                .ok_or(TokenBucketError::ClockUnderflow)?,
+            None => now
+                .checked_add(scale(effective_last_refreshed - now)?)
+                .ok_or(TokenBucketError::CapacityOverflow)?,
+        };
+        self.last_refreshed = fits_full_refresh(last_refreshed, max_refresh_duration)?;
+        self.refresh_interval = refresh_interval;
+        self.max_refresh_duration = max_refresh_duration;
+        Ok(())
+    }
+
+    pub fn time_until_full(&self) -> Duration {
+        self.last_refreshed
+            .checked_add(self.max_refresh_duration)
+            .map_or(Duration::MAX, |full| {
+                full.saturating_duration_since(self.clock.now())
+            })
+    }
+
+    /// Zero if n tokens can be taken right now
+    pub fn time_until_available(&self, n: u64) -> Result<Duration, TokenBucketError> {
+        let now = self.clock.now();
+        Ok(self
+            .get_next_refreshed_time(n, now)?
+            .saturating_duration_since(now))
+    }
+
+    pub fn try_take(&mut self) -> Result<(), TokenBucketError> {
+        self.try_take_n(1)
+    }
+
+    /// Takes all n tokens or none of them
+    pub fn try_take_n(&mut self, n: u64) -> Result<(), TokenBucketError> {
+        self.check_n(n)?.map_err(TokenBucketError::from)
+    }
+
+    /// Same as try_take but reports when the token will be available,
+    /// outer error when the clock can't represent the refill after it
+    pub fn check(&mut self) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        self.check_n(1)
+    }
+
+    /// Outer error when n can never fit, inner when the tokens aren't available yet
+    pub fn check_n(&mut self, n: u64) -> Result<Result<(), NotUntil>, TokenBucketError> {
+        Ok(self
+            .peek_n(n)?
+            .map(|new_last_refreshed| self.last_refreshed = new_last_refreshed))
+    }
+
+    /// Same as check_n but leaves the bucket alone, commit applies the returned time
+    pub(crate) fn peek_n(&self, n: u64) -> Result<Result<Instant, NotUntil>, TokenBucketError> {
+        let now = self.clock.now();
+        let new_last_refreshed = self.get_next_refreshed_time(n, now)?;
+        if let Some(wait) = new_last_refreshed
+            .checked_duration_since(now)
+            .filter(|wait| !wait.is_zero())
+        {
+            return Ok(Err(NotUntil {
+                earliest: new_last_refreshed,
+                wait,
+            }));
+        }
+        Ok(Ok(new_last_refreshed))
+    }
+
+    pub(crate) fn commit(&mut self, new_last_refreshed: Instant) {
         self.last_refreshed = new_last_refreshed;
-        Some(())
     }
 
-    pub fn take(&mut self) -> Option<()> {
-        let effective_last_refreshed = self.get_effective_last_refreshed()?;
-        let new_last_refreshed = effective_last_refreshed + self.refresh_interval;
-        if let None = Instant::now().checked_duration_since(new_last_refreshed) {
-                std::thread::sleep(new_last_refreshed.duration_since(Instant::now()));
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub fn take(&mut self) -> Result<(), TokenBucketError> {
+        self.take_n(1)
+    }
+
+    /// Fails instead of waiting when n is more than max capacity
+    pub fn take_n(&mut self, n: u64) -> Result<(), TokenBucketError> {
+        let now = self.clock.now();
+        let new_last_refreshed = self.get_next_refreshed_time(n, now)?;
+        self.sleep_and_take(new_last_refreshed, now);
+        Ok(())
+    }
+
+    /// Fails right away without sleeping if the tokens won't be available within timeout
+    pub fn take_timeout(&mut self, n: u64, timeout: Duration) -> Result<(), TokenBucketError> {
+        match self.clock.now().checked_add(timeout) {
+            Some(deadline) => self.take_deadline(n, deadline),
+            None => self.take_n(n),
+        }
+    }
+
+    /// Fails right away without sleeping if the tokens won't be available by deadline
+    pub fn take_deadline(&mut self, n: u64, deadline: Instant) -> Result<(), TokenBucketError> {
+        let now = self.clock.now();
+        let new_last_refreshed = self.get_next_refreshed_time(n, now)?;
+        if new_last_refreshed > deadline {
+            return Err(TokenBucketError::InsufficientTokens {
+                retry_after: new_last_refreshed - now,
+            });
+        }
+        self.sleep_and_take(new_last_refreshed, now);
+        Ok(())
+    }
+
+    fn sleep_and_take(&mut self, new_last_refreshed: Instant, now: Instant) {
+        if let Some(wait) = new_last_refreshed.checked_duration_since(now) {
+            self.clock.sleep(wait);
         };
         self.last_refreshed = new_last_refreshed;
-        Some(())
+    }
+
+    pub async fn take_async(&mut self) -> Result<(), TokenBucketError> {
+        self.take_n_async(1).await
+    }
+
+    /// Same as take_n but waits without blocking the thread
+    pub async fn take_n_async(&mut self, n: u64) -> Result<(), TokenBucketError> {
+        let now = self.clock.now();
+        let new_last_refreshed = self.get_next_refreshed_time(n, now)?;
+        if let Some(wait) = new_last_refreshed.checked_duration_since(now) {
+            self.clock.sleep_async(wait).await;
+        };
+        self.last_refreshed = new_last_refreshed;
+        Ok(())
+    }
+
+    /// Takes n tokens now even if that puts the bucket into debt,
+    /// the reservation tells when they may be used
+    pub fn reserve(&mut self, n: u64) -> Result<Reservation<C>, TokenBucketError>
+    where
+        C: Clone,
+    {
+        let now = self.clock.now();
+        let new_last_refreshed = self.get_next_refreshed_time(n, now)?;
+        self.last_refreshed = new_last_refreshed;
+        Ok(Reservation::new(
+            cmp::max(new_last_refreshed, now),
+            n,
+            self.id,
+            self.clock.clone(),
+        ))
+    }
+
+    /// Gives back n tokens, anything past max capacity is dropped
+    pub fn refund(&mut self, n: u64) -> Result<(), TokenBucketError> {
+        let now = self.clock.now();
+        let effective_last_refreshed = self.get_effective_last_refreshed(now);
+        let until_full = match now.checked_duration_since(effective_last_refreshed) {
+            Some(elapsed) => self.max_refresh_duration - elapsed,
+            None => self
+                .max_refresh_duration
+                .saturating_add(effective_last_refreshed - now),
+        };
+        let refund_duration = checked_mul_duration(self.refresh_interval, n)
+            .map_or(until_full, |refund_duration| {
+                cmp::min(refund_duration, until_full)
+            });
+        self.last_refreshed = effective_last_refreshed
+            .checked_sub(refund_duration)
+            .ok_or(TokenBucketError::ClockUnderflow)?;
+        Ok(())
     }
 }
 
-// TODO: write tests
-impl fmt::Debug for TokenBucket {
+fn checked_mul_duration(duration: Duration, n: u64) -> Option<Duration> {
+    duration_from_nanos(duration.as_nanos().checked_mul(u128::from(n))?)
+}
+
+// duration * numerator / denominator without losing nanos in between
+fn checked_scale_duration(
+    duration: Duration,
+    numerator: Duration,
+    denominator: Duration,
+) -> Option<Duration> {
+    let nanos = duration.as_nanos().checked_mul(numerator.as_nanos())?;
+    duration_from_nanos(nanos.checked_div(denominator.as_nanos())?)
+}
+
+// Every last_refreshed must leave room for a full refresh after it,
+// otherwise even a single token would overflow the Instant
+fn fits_full_refresh(
+    last_refreshed: Instant,
+    max_refresh_duration: Duration,
+) -> Result<Instant, TokenBucketError> {
+    last_refreshed
+        .checked_add(max_refresh_duration)
+        .map(|_| last_refreshed)
+        .ok_or(TokenBucketError::CapacityOverflow)
+}
+
+pub(crate) fn duration_from_nanos(nanos: u128) -> Option<Duration> {
+    let secs = u64::try_from(nanos / 1_000_000_000).ok()?;
+    Some(Duration::new(secs, (nanos % 1_000_000_000) as u32))
+}
+
+impl<C: Clock> fmt::Debug for TokenBucket<C> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        match self.get_effective_last_refreshed() {
-            Some(last_refreshed) => {
-                let elapsed = Instant::now()
-                    .checked_duration_since(last_refreshed)
-                    .ok_or(fmt::Error)?;
-                let count = elapsed
-                    .as_millis()
-                    .checked_div(self.refresh_interval.as_millis())
-                    .or(Some(0));
-                f.debug_tuple("TokenBucket").field(&count).finish()
-            }
-            None => Err(fmt::Error),
-        }
+        f.debug_struct("TokenBucket")
+            .field("available", &self.available())
+            .field("capacity", &self.capacity())
+            .field("refill_interval", &self.refresh_interval)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test_new {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn rejects_zero_capacity() {
+        assert_eq!(
+            TokenBucket::new(Duration::from_millis(1), 0, 0).unwrap_err(),
+            TokenBucketError::InvalidCapacity
+        );
+    }
+
+    #[test]
+    fn rejects_zero_interval() {
+        assert_eq!(
+            TokenBucket::new(Duration::ZERO, 1, 1).unwrap_err(),
+            TokenBucketError::InvalidInterval
+        );
+    }
+
+    #[test]
+    fn rejects_overflowing_capacity() {
+        assert_eq!(
+            TokenBucket::new(Duration::from_secs(2), u64::MAX, 0).unwrap_err(),
+            TokenBucketError::CapacityOverflow
+        );
+    }
+
+    #[test]
+    fn rejects_refresh_past_the_clock_range() {
+        assert_eq!(
+            TokenBucket::new(Duration::from_secs(u64::MAX), 1, 0).unwrap_err(),
+            TokenBucketError::CapacityOverflow
+        );
+    }
+
+    #[test]
+    fn supports_sub_millisecond_intervals() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_micros(100), 10_000, 0, clock.clone()).unwrap();
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(tb.try_take_n(10_000), Ok(()));
+        assert!(tb.try_take().is_err());
+        clock.advance(Duration::from_micros(100));
+        assert_eq!(tb.try_take(), Ok(()));
     }
 }
 
 #[cfg(test)]
 mod test_try_take {
     use super::*;
+    use crate::clock::MockClock;
 
     #[test]
     fn initializes_with_proper_tokens() {
         // needs to have min(max capacity , initial_capacity)
-        let mut tb = TokenBucket::new(1, 1, 2).unwrap();
-        assert!(tb.try_take().is_some());
-        assert!(tb.try_take().is_none());
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(1), 1, 2, MockClock::new()).unwrap();
+        assert!(tb.try_take().is_ok());
+        assert!(tb.try_take().is_err());
     }
 
     #[test]
     fn can_take_all_initial() {
-        let mut tb = TokenBucket::new(1, 2, 2).unwrap();
-        assert!(tb.try_take().is_some());
-        assert!(tb.try_take().is_some());
-        assert!(tb.try_take().is_none());
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(1), 2, 2, MockClock::new()).unwrap();
+        assert!(tb.try_take().is_ok());
+        assert!(tb.try_take().is_ok());
+        assert!(tb.try_take().is_err());
     }
 
     #[test]
     fn can_take_generated_tokens() {
-        let mut tb = TokenBucket::new(100, 2, 1).unwrap();
-        assert!(tb.try_take().is_some());
-        thread::sleep(Duration::from_millis(100));
-        assert!(tb.try_take().is_some());
-        assert!(tb.try_take().is_none());
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(100), 2, 1, clock.clone()).unwrap();
+        assert!(tb.try_take().is_ok());
+        clock.advance(Duration::from_millis(100));
+        assert!(tb.try_take().is_ok());
+        assert!(tb.try_take().is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_check {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn reports_earliest_and_wait() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(100), 2, 1, clock.clone()).unwrap();
+        assert_eq!(tb.check(), Ok(Ok(())));
+        clock.advance(Duration::from_millis(40));
+        let not_until = tb.check().unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_millis(60));
+        assert_eq!(not_until.earliest, clock.now() + Duration::from_millis(60));
+        clock.advance(not_until.wait);
+        assert_eq!(tb.check(), Ok(Ok(())));
+    }
+
+    #[test]
+    fn check_n_separates_capacity_from_waiting() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(10), 5, 2, clock.clone()).unwrap();
+        assert_eq!(tb.check_n(6), Err(TokenBucketError::CapacityOverflow));
+        let not_until = tb.check_n(4).unwrap().unwrap_err();
+        assert_eq!(not_until.wait, Duration::from_millis(20));
+        assert_eq!(tb.check_n(2), Ok(Ok(())));
+    }
+
+    #[test]
+    fn refill_past_the_clock_range_is_an_error() {
+        let clock = MockClock::new();
+        let interval = Duration::from_secs(u64::MAX / 4);
+        let mut tb = TokenBucket::with_clock(interval, 1, 0, clock.clone()).unwrap();
+        assert_eq!(tb.take_n(1), Err(TokenBucketError::CapacityOverflow));
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+        assert_eq!(tb.check(), Err(TokenBucketError::CapacityOverflow));
+        assert_eq!(
+            tb.reserve(1).err(),
+            Some(TokenBucketError::CapacityOverflow)
+        );
+        assert_eq!(tb.time_until_full(), interval);
     }
 }
 
 #[cfg(test)]
 mod test_take {
     use super::*;
+    use crate::clock::MockClock;
 
     #[test]
     fn can_take_all_initial() {
-        let mut tb = TokenBucket::new(50, 3, 3).unwrap();
-        assert!(tb.take().is_some());
-        assert!(tb.take().is_some());
-        assert!(tb.take().is_some());
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(50), 3, 3, clock.clone()).unwrap();
+        assert!(tb.take().is_ok());
+        assert!(tb.take().is_ok());
+        assert!(tb.take().is_ok());
+        assert_eq!(clock.elapsed(), Duration::ZERO);
     }
 
     #[test]
     fn can_take_after_waiting() {
-        let mut tb = TokenBucket::new(50, 2, 1).unwrap();
-        assert!(tb.take().is_some());
-        let now = Instant::now();
-        assert!(tb.take().is_some());
-        let elapsed = now.elapsed().as_millis();
-        assert!(elapsed >= 50 && elapsed <= 55);
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(50), 2, 1, clock.clone()).unwrap();
+        assert!(tb.take().is_ok());
+        assert!(tb.take().is_ok());
+        assert_eq!(clock.elapsed(), Duration::from_millis(50));
     }
 
     #[test]
     fn can_take_multiple_after_waiting() {
-        let mut tb = TokenBucket::new(10, 2, 0).unwrap();
-        let now = Instant::now();
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(10), 2, 0, clock.clone()).unwrap();
         for _ in 0..10 {
-            assert!(tb.take().is_some());
+            assert!(tb.take().is_ok());
         }
-        let elapsed = now.elapsed().as_millis();
-        let bound = 100;
-        assert!(elapsed >= bound && elapsed <= bound + 5);
+        assert_eq!(clock.elapsed(), Duration::from_millis(100));
     }
 
     #[test]
     fn can_take_generated_tokens() {
-        let mut tb = TokenBucket::new(50, 2, 0).unwrap();
-        thread::sleep(Duration::from_millis(100));
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(50), 2, 0, clock.clone()).unwrap();
+        clock.advance(Duration::from_millis(100));
+        assert!(tb.take().is_ok());
+        assert!(tb.take().is_ok());
+        assert_eq!(clock.elapsed(), Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+mod test_try_take_n {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn takes_all_or_nothing() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(10), 5, 3, clock.clone()).unwrap();
+        assert!(tb.try_take_n(4).is_err());
+        assert!(tb.try_take_n(3).is_ok());
+        assert!(tb.try_take().is_err());
+        clock.advance(Duration::from_millis(20));
+        assert!(tb.try_take_n(3).is_err());
+        assert!(tb.try_take_n(2).is_ok());
+    }
+
+    #[test]
+    fn rejects_more_than_max_capacity() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(10), 5, 5, clock.clone()).unwrap();
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(tb.try_take_n(6), Err(TokenBucketError::CapacityOverflow));
+        assert!(tb.try_take_n(5).is_ok());
+    }
+
+    #[test]
+    fn rejects_overflowing_counts() {
+        let mut tb = TokenBucket::new(Duration::from_millis(10), 5, 5).unwrap();
+        assert_eq!(
+            tb.try_take_n(u64::MAX),
+            Err(TokenBucketError::CapacityOverflow)
+        );
+        assert!(tb.try_take_n(5).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test_take_n {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn waits_for_all_tokens() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(10), 5, 1, clock.clone()).unwrap();
+        assert!(tb.take_n(4).is_ok());
+        assert_eq!(clock.elapsed(), Duration::from_millis(30));
+        assert!(tb.try_take().is_err());
+    }
+
+    #[test]
+    fn fails_instead_of_blocking_forever() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(10), 5, 0, clock.clone()).unwrap();
+        assert_eq!(tb.take_n(6), Err(TokenBucketError::CapacityOverflow));
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+    }
+}
+
+#[cfg(test)]
+mod test_take_deadline {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn waits_when_available_before_deadline() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(10), 5, 0, clock.clone()).unwrap();
+        let deadline = clock.now() + Duration::from_millis(30);
+        assert_eq!(tb.take_deadline(3, deadline), Ok(()));
+        assert_eq!(clock.now(), deadline);
+        assert!(tb.try_take().is_err());
+    }
+
+    #[test]
+    fn fails_without_sleeping_when_too_late() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(10), 5, 1, clock.clone()).unwrap();
+        let deadline = clock.now() + Duration::from_millis(15);
+        assert_eq!(
+            tb.take_deadline(3, deadline),
+            Err(TokenBucketError::InsufficientTokens {
+                retry_after: Duration::from_millis(20)
+            })
+        );
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+        assert_eq!(tb.available(), 1);
+    }
+
+    #[test]
+    fn timeout() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(10), 5, 0, clock.clone()).unwrap();
+        assert!(tb.take_timeout(1, Duration::from_millis(9)).is_err());
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+        assert_eq!(tb.take_timeout(1, Duration::from_millis(10)), Ok(()));
+        assert_eq!(clock.elapsed(), Duration::from_millis(10));
+        assert_eq!(tb.take_timeout(1, Duration::MAX), Ok(()));
+        assert_eq!(clock.elapsed(), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn timeout_with_real_clock() {
+        let mut tb = TokenBucket::new(Duration::from_millis(50), 2, 0).unwrap();
         let now = Instant::now();
-        assert!(tb.take().is_some());
-        assert!(tb.take().is_some());
-        let elapsed = now.elapsed().as_millis();
-        assert!(elapsed == 0);
+        assert!(tb.take_timeout(1, Duration::from_millis(20)).is_err());
+        assert!(now.elapsed() < Duration::from_millis(20));
+        assert_eq!(tb.take_timeout(1, Duration::from_millis(100)), Ok(()));
+        assert!(now.elapsed() >= Duration::from_millis(50));
+    }
+}
+
+#[cfg(test)]
+mod test_take_async {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::delay::block_on;
+
+    #[test]
+    fn can_take_all_initial() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(50), 3, 3, clock.clone()).unwrap();
+        block_on(async {
+            for _ in 0..3 {
+                assert!(tb.take_async().await.is_ok());
+            }
+        });
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+    }
+
+    #[test]
+    fn waits_on_the_bucket_clock() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(50), 2, 1, clock.clone()).unwrap();
+        let now = Instant::now();
+        assert!(block_on(tb.take_async()).is_ok());
+        assert!(block_on(tb.take_async()).is_ok());
+        assert_eq!(clock.elapsed(), Duration::from_millis(50));
+        assert!(now.elapsed() < Duration::from_millis(50));
+        assert!(tb.try_take().is_err());
+        clock.advance(Duration::from_millis(50));
+        assert!(tb.try_take().is_ok());
+    }
+
+    #[test]
+    fn fails_instead_of_waiting_forever() {
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(50), 2, 0, MockClock::new()).unwrap();
+        assert!(block_on(tb.take_n_async(3)).is_err());
+    }
+}
+
+#[cfg(all(test, not(feature = "tokio")))]
+mod test_take_async_system_clock {
+    use super::*;
+    use crate::delay::block_on;
+
+    #[test]
+    fn can_take_after_waiting() {
+        let mut tb = TokenBucket::new(Duration::from_millis(50), 2, 1).unwrap();
+        assert!(block_on(tb.take_async()).is_ok());
+        let now = Instant::now();
+        assert!(block_on(tb.take_async()).is_ok());
+        assert!(now.elapsed() >= Duration::from_millis(50));
+        assert!(tb.try_take().is_err());
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod test_take_async_tokio {
+    use super::*;
+
+    #[tokio::test]
+    async fn can_take_after_waiting() {
+        let mut tb = TokenBucket::new(Duration::from_millis(50), 2, 1).unwrap();
+        assert!(tb.take_async().await.is_ok());
+        let now = Instant::now();
+        assert!(tb.take_async().await.is_ok());
+        assert!(now.elapsed() >= Duration::from_millis(50));
+        assert!(tb.try_take().is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_refund {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn returns_taken_tokens() {
+        let mut tb = TokenBucket::new(Duration::from_secs(1), 5, 5).unwrap();
+        tb.try_take_n(3).unwrap();
+        tb.refund(2).unwrap();
+        assert_eq!(tb.available(), 4);
+    }
+
+    #[test]
+    fn cannot_exceed_capacity() {
+        let mut tb = TokenBucket::new(Duration::from_secs(1), 5, 5).unwrap();
+        tb.try_take_n(2).unwrap();
+        tb.refund(10).unwrap();
+        assert_eq!(tb.available(), 5);
+        assert!(tb.try_take_n(6).is_err());
+        tb.refund(u64::MAX).unwrap();
+        assert_eq!(tb.available(), 5);
+    }
+
+    #[test]
+    fn keeps_partial_refill_progress() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(10), 5, 0, clock.clone()).unwrap();
+        clock.advance(Duration::from_millis(15));
+        tb.refund(2).unwrap();
+        assert_eq!(tb.available(), 3);
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(tb.available(), 4);
+    }
+
+    #[test]
+    fn full_bucket_is_unchanged() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(10), 2, 2, clock.clone()).unwrap();
+        clock.advance(Duration::from_secs(1));
+        tb.refund(1).unwrap();
+        assert_eq!(tb.available(), 2);
+        assert_eq!(tb.try_take_n(2), Ok(()));
+        assert!(tb.try_take().is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_reconfigure {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn bucket(clock: &MockClock) -> TokenBucket<MockClock> {
+        TokenBucket::with_clock(Duration::from_millis(10), 10, 0, clock.clone()).unwrap()
+    }
+
+    #[test]
+    fn set_rate_keeps_tokens_and_progress() {
+        let clock = MockClock::new();
+        let mut tb = bucket(&clock);
+        clock.advance(Duration::from_millis(45));
+        tb.set_rate(1, Duration::from_millis(100)).unwrap();
+        assert_eq!(tb.available(), 4);
+        assert_eq!(tb.refill_interval(), Duration::from_millis(100));
+        assert_eq!(tb.capacity(), 10);
+        assert_eq!(tb.time_until_available(5), Ok(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn set_refill_interval_keeps_debt() {
+        let clock = MockClock::new();
+        let mut tb = bucket(&clock);
+        tb.reserve(2).unwrap();
+        tb.set_refill_interval(Duration::from_millis(5)).unwrap();
+        assert_eq!(tb.time_until_available(1), Ok(Duration::from_millis(15)));
+    }
+
+    #[test]
+    fn set_capacity_clamps_tokens() {
+        let clock = MockClock::new();
+        let mut tb = bucket(&clock);
+        clock.advance(Duration::from_secs(1));
+        tb.set_capacity(4).unwrap();
+        assert_eq!(tb.available(), 4);
+        assert_eq!(tb.capacity(), 4);
+        tb.set_capacity(8).unwrap();
+        assert_eq!(tb.available(), 4);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(tb.available(), 8);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let clock = MockClock::new();
+        let mut tb = bucket(&clock);
+        assert_eq!(tb.set_capacity(0), Err(TokenBucketError::InvalidCapacity));
+        assert_eq!(
+            tb.set_rate(0, Duration::from_secs(1)),
+            Err(TokenBucketError::InvalidInterval)
+        );
+        assert_eq!(
+            tb.set_refill_interval(Duration::ZERO),
+            Err(TokenBucketError::InvalidInterval)
+        );
+        assert_eq!(tb.refill_interval(), Duration::from_millis(10));
+        assert_eq!(tb.capacity(), 10);
+    }
+}
+
+#[cfg(test)]
+mod test_available {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn counts_refilled_tokens_up_to_capacity() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(10), 5, 2, clock.clone()).unwrap();
+        assert_eq!(tb.available(), 2);
+        assert_eq!(tb.capacity(), 5);
+        assert_eq!(tb.refill_interval(), Duration::from_millis(10));
+        clock.advance(Duration::from_millis(25));
+        assert_eq!(tb.available(), 4);
+        tb.try_take_n(3).unwrap();
+        assert_eq!(tb.available(), 1);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(tb.available(), 5);
+    }
+
+    #[test]
+    fn does_not_consume() {
+        let tb = TokenBucket::new(Duration::from_secs(1), 3, 3).unwrap();
+        assert_eq!(tb.available(), 3);
+        assert_eq!(tb.available(), 3);
+    }
+}
+
+#[cfg(test)]
+mod test_time_until {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn full() {
+        let clock = MockClock::new();
+        let tb = TokenBucket::with_clock(Duration::from_millis(10), 5, 2, clock.clone()).unwrap();
+        assert_eq!(tb.time_until_full(), Duration::from_millis(30));
+        clock.advance(Duration::from_millis(45));
+        assert_eq!(tb.time_until_full(), Duration::ZERO);
+    }
+
+    #[test]
+    fn available() {
+        let clock = MockClock::new();
+        let tb = TokenBucket::with_clock(Duration::from_millis(10), 5, 2, clock.clone()).unwrap();
+        assert_eq!(tb.time_until_available(2), Ok(Duration::ZERO));
+        assert_eq!(tb.time_until_available(4), Ok(Duration::from_millis(20)));
+        assert_eq!(
+            tb.time_until_available(6),
+            Err(TokenBucketError::CapacityOverflow)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_debug {
+    use super::*;
+
+    #[test]
+    fn shows_state() {
+        let tb = TokenBucket::new(Duration::from_secs(1), 3, 2).unwrap();
+        assert_eq!(
+            format!("{:?}", tb),
+            "TokenBucket { available: 2, capacity: 3, refill_interval: 1s }"
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_with_clock {
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn try_take_refills_as_clock_advances() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(100), 2, 1, clock.clone()).unwrap();
+        assert!(tb.try_take().is_ok());
+        assert!(tb.try_take().is_err());
+        clock.advance(Duration::from_millis(99));
+        assert!(tb.try_take().is_err());
+        clock.advance(Duration::from_millis(1));
+        assert!(tb.try_take().is_ok());
+        assert!(tb.try_take().is_err());
+    }
+
+    #[test]
+    fn reports_retry_after() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(100), 2, 0, clock.clone()).unwrap();
+        clock.advance(Duration::from_millis(30));
+        assert_eq!(
+            tb.try_take(),
+            Err(TokenBucketError::InsufficientTokens {
+                retry_after: Duration::from_millis(70)
+            })
+        );
+    }
+
+    #[test]
+    fn refill_stops_at_max_capacity() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(10), 3, 0, clock.clone()).unwrap();
+        clock.advance(Duration::from_secs(60));
+        for _ in 0..3 {
+            assert!(tb.try_take().is_ok());
+        }
+        assert!(tb.try_take().is_err());
+    }
+
+    #[test]
+    fn take_advances_clock_instead_of_sleeping() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(50), 2, 1, clock.clone()).unwrap();
+        assert!(tb.take().is_ok());
+        assert!(tb.take().is_ok());
+        assert_eq!(clock.elapsed(), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn simulates_hours_of_traffic() {
+        let clock = MockClock::new();
+        let mut tb =
+            TokenBucket::with_clock(Duration::from_millis(1000), 10, 10, clock.clone()).unwrap();
+        let mut taken = 0;
+        for _ in 0..(3 * 60 * 60) {
+            while tb.try_take().is_ok() {
+                taken += 1;
+            }
+            clock.advance(Duration::from_secs(1));
+        }
+        assert_eq!(taken, 10 + 3 * 60 * 60 - 1);
     }
 }